@@ -0,0 +1,462 @@
+use anyhow::Result;
+use resp::{Decoder, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bulk_array,
+    config::Config,
+    db::{Data, HashField, RedisValue, Store, StreamId},
+    execute_command, extract_command, format_float, keys,
+    stream::claim_args,
+    unpack_bulk_string,
+};
+
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of elements per command when rewriting collections.
+const REWRITE_BATCH_SIZE: usize = 64;
+
+/// When appended commands are flushed to disk, like `appendfsync` in Redis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(anyhow::anyhow!("invalid appendfsync policy: {s}")),
+        }
+    }
+}
+
+struct AofState {
+    /// The open log, or `None` while append only persistence is disabled.
+    file: Option<File>,
+    /// Commands appended while a rewrite is running, added to the new log
+    /// once the rewrite is done.
+    rewrite_buffer: Option<Vec<u8>>,
+    /// Whether commands were written since the last fsync.
+    unsynced: bool,
+    /// The database the last logged `SELECT` switched to, if any, so that
+    /// `SELECT` is only logged when the database changes.
+    selected_db: Option<usize>,
+}
+
+/// The append only file: every write command is logged so the store can be
+/// rebuilt by replaying it on startup.
+#[derive(Clone)]
+pub struct Aof {
+    path: PathBuf,
+    config: Config,
+    state: Arc<Mutex<AofState>>,
+}
+
+impl Aof {
+    pub fn new(path: PathBuf, config: Config, enabled: bool) -> Result<Self> {
+        let file = if enabled {
+            Some(OpenOptions::new().create(true).append(true).open(&path)?)
+        } else {
+            None
+        };
+        let aof = Aof {
+            path,
+            config,
+            state: Arc::new(Mutex::new(AofState {
+                file,
+                rewrite_buffer: None,
+                unsynced: false,
+                selected_db: None,
+            })),
+        };
+        if enabled {
+            let aof = aof.clone();
+            thread::spawn(move || loop {
+                thread::sleep(FSYNC_INTERVAL);
+                if aof.config.read().appendfsync == FsyncPolicy::EverySec {
+                    aof.fsync();
+                }
+            });
+        }
+        Ok(aof)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().file.is_some()
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.state.lock().unwrap().rewrite_buffer.is_some()
+    }
+
+    /// Appends a write command run against database `db` to the log, preceded
+    /// by a `SELECT` if the log was on another database. Relative expiries
+    /// are stored as absolute timestamps so that replaying the log later
+    /// keeps the same deadline.
+    pub fn append(&self, db: usize, command: &Value) {
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() {
+            return;
+        }
+        let policy = self.config.read().appendfsync;
+        let mut bytes = Vec::new();
+        if state.selected_db != Some(db) {
+            bytes.extend(bulk_array(vec!["SELECT".to_string(), db.to_string()]).encode());
+            state.selected_db = Some(db);
+        }
+        bytes.extend(absolute_expiry(command).encode());
+        if let Some(buffer) = state.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&bytes);
+        }
+        let file = state.file.as_mut().unwrap();
+        if let Err(e) = file.write_all(&bytes) {
+            println!("error writing to {}: {}", self.path.display(), e);
+            return;
+        }
+        if policy == FsyncPolicy::Always {
+            if let Err(e) = file.sync_data() {
+                println!("error syncing {}: {}", self.path.display(), e);
+            }
+        } else {
+            state.unsynced = true;
+        }
+    }
+
+    fn fsync(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.unsynced {
+            return;
+        }
+        if let Some(file) = state.file.as_ref() {
+            if let Err(e) = file.sync_data() {
+                println!("error syncing {}: {}", self.path.display(), e);
+                return;
+            }
+        }
+        state.unsynced = false;
+    }
+
+    /// Compacts the log on a background thread by writing the smallest set of
+    /// commands that recreates the current dataset. Returns false if a rewrite
+    /// is already running.
+    pub fn rewrite(&self, store: &Store) -> bool {
+        let databases = {
+            // Holding the write order means no write can land in the snapshot
+            // without also reaching the rewrite buffer, or the other way round.
+            let _order = store.order_writes();
+            let mut state = self.state.lock().unwrap();
+            if state.rewrite_buffer.is_some() {
+                return false;
+            }
+            state.rewrite_buffer = Some(Vec::new());
+            // The buffered commands follow the rewritten ones, which may end
+            // on any database, so they have to start with a `SELECT`.
+            state.selected_db = None;
+            store.snapshot()
+        };
+        let aof = self.clone();
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            for (db, entries) in databases.into_iter().enumerate() {
+                if entries.is_empty() {
+                    continue;
+                }
+                buf.extend(bulk_array(vec!["SELECT".to_string(), db.to_string()]).encode());
+                for (key, data) in entries {
+                    for command in rewrite_commands(key, data) {
+                        buf.extend(bulk_array(command).encode());
+                    }
+                }
+            }
+            if let Err(e) = aof.finish_rewrite(buf) {
+                println!("rewriting {} failed: {}", aof.path.display(), e);
+            }
+        });
+        true
+    }
+
+    /// Writes the compacted log together with the commands buffered during the
+    /// rewrite, then swaps it in place of the current log.
+    fn finish_rewrite(&self, mut buf: Vec<u8>) -> Result<()> {
+        let tmp = self
+            .path
+            .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let mut state = self.state.lock().unwrap();
+        let buffered = state.rewrite_buffer.take().unwrap_or_default();
+        buf.extend_from_slice(&buffered);
+        fs::write(&tmp, &buf)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        if state.file.is_some() {
+            state.file = Some(OpenOptions::new().append(true).open(&self.path)?);
+        }
+        println!("rewrote {} ({} bytes)", self.path.display(), buf.len());
+        Ok(())
+    }
+}
+
+/// Replays the log at `path` into the store. Returns the number of commands
+/// applied, or `None` if there is no log to replay.
+pub fn load(path: &Path, store: &Store) -> Result<Option<usize>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut decoder = Decoder::new(BufReader::new(file));
+    // The log switches databases with `SELECT`, which only affects this replay.
+    let mut store = store.clone();
+    let mut count = 0;
+    loop {
+        let value = match decoder.decode() {
+            Ok(value) => value,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                println!("truncated command at the end of {}: {}", path.display(), e);
+                break;
+            }
+        };
+        // A command that fails now (say, an LSET on a list that has since
+        // expired) only affects itself, so log it and keep replaying.
+        let (command, args) = match extract_command(&value) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("error replaying {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let result = match command.to_lowercase().as_str() {
+            "select" => keys::handle_select(args, &mut store),
+            command => execute_command(command, args, &store),
+        };
+        match result {
+            Ok(Value::Error(e)) => println!("error replaying {}: {}", path.display(), e),
+            Err(e) => println!("error replaying {}: {}", path.display(), e),
+            Ok(_) => {}
+        }
+        count += 1;
+    }
+    Ok(Some(count))
+}
+
+/// Returns the commands that recreate a key with its value and expiry.
+fn rewrite_commands(key: String, data: RedisValue) -> Vec<Vec<String>> {
+    let expiry = data.expiry.map(unix_millis);
+    let mut commands = match data.value {
+        Data::String(value) => {
+            let mut command = vec!["SET".to_string(), key, value];
+            if let Some(ms) = expiry {
+                command.push("PXAT".to_string());
+                command.push(ms.to_string());
+            }
+            return vec![command];
+        }
+        Data::List(list) => batched("RPUSH", &key, list.into_iter().map(|e| vec![e])),
+        Data::Set(set) => batched("SADD", &key, set.into_iter().map(|m| vec![m])),
+        Data::SortedSet(set) => batched(
+            "ZADD",
+            &key,
+            set.iter()
+                .map(|(member, score)| vec![format_float(score), member.clone()]),
+        ),
+        Data::Hash(hash) => {
+            let fields: Vec<(String, HashField)> = hash.into_iter().collect();
+            let pairs = fields
+                .iter()
+                .map(|(name, field)| vec![name.clone(), field.value.clone()]);
+            let mut commands = batched("HSET", &key, pairs);
+            for (name, field) in fields {
+                if let Some(expiry) = field.expiry {
+                    commands.push(vec![
+                        "HPEXPIREAT".to_string(),
+                        key.clone(),
+                        unix_millis(expiry).to_string(),
+                        "FIELDS".to_string(),
+                        "1".to_string(),
+                        name,
+                    ]);
+                }
+            }
+            commands
+        }
+        Data::Stream(stream) => {
+            let mut commands: Vec<Vec<String>> = stream
+                .entries
+                .into_iter()
+                .map(|(id, fields)| {
+                    let mut command = vec!["XADD".to_string(), key.clone(), id.to_string()];
+                    command.extend(fields.into_iter().flat_map(|(field, value)| [field, value]));
+                    command
+                })
+                .collect();
+            // An empty stream still remembers its last ID, so recreate it
+            // with an entry that is trimmed right away.
+            if commands.is_empty() && stream.last_id != StreamId::MIN {
+                commands.push(
+                    [
+                        "XADD",
+                        &key,
+                        "MAXLEN",
+                        "0",
+                        &stream.last_id.to_string(),
+                        "",
+                        "",
+                    ]
+                    .map(String::from)
+                    .to_vec(),
+                );
+            }
+            for (name, group) in &stream.groups {
+                let last_id = group.last_id.to_string();
+                commands.push(
+                    ["XGROUP", "CREATE", &key, name, &last_id, "MKSTREAM"]
+                        .map(String::from)
+                        .to_vec(),
+                );
+                for consumer in group.consumers.keys() {
+                    commands.push(
+                        ["XGROUP", "CREATECONSUMER", &key, name, consumer]
+                            .map(String::from)
+                            .to_vec(),
+                    );
+                }
+                for (id, pending) in &group.pending {
+                    commands.push(claim_args(&key, name, &[*id], pending, group.last_id));
+                }
+            }
+            commands
+        }
+    };
+    if let Some(ms) = expiry {
+        commands.push(vec!["PEXPIREAT".to_string(), key, ms.to_string()]);
+    }
+    commands
+}
+
+/// Splits the arguments of a variadic command such as `RPUSH key element
+/// [element ...]` over as many commands as needed to keep each one short.
+fn batched(command: &str, key: &str, items: impl Iterator<Item = Vec<String>>) -> Vec<Vec<String>> {
+    let items: Vec<Vec<String>> = items.collect();
+    items
+        .chunks(REWRITE_BATCH_SIZE)
+        .map(|chunk| {
+            let mut args = vec![command.to_string(), key.to_string()];
+            args.extend(chunk.iter().flatten().cloned());
+            args
+        })
+        .collect()
+}
+
+/// Turns `SET ... PX|EX <ttl>` into `SET ... PXAT <timestamp>`, likewise for
+/// `GETEX`, and the `EXPIRE` and `HEXPIRE` families into `PEXPIREAT` and
+/// `HPEXPIREAT`.
+pub fn absolute_expiry(command: &Value) -> Value {
+    let parts = match command {
+        Value::Array(parts) => parts,
+        _ => return command.clone(),
+    };
+    let strings: Vec<String> = match parts.iter().map(unpack_bulk_string).collect() {
+        Ok(strings) => strings,
+        Err(_) => return command.clone(),
+    };
+    let name = strings.first().map(|c| c.to_lowercase());
+    let rewritten = match name.as_deref() {
+        Some("set") => absolute_set(strings, 3),
+        Some("getex") => absolute_set(strings, 2),
+        Some("expire" | "pexpire" | "expireat") => absolute_expire(strings),
+        Some("hexpire" | "hpexpire" | "hexpireat") => absolute_hexpire(strings),
+        _ => None,
+    };
+    rewritten.map(bulk_array).unwrap_or_else(|| command.clone())
+}
+
+fn absolute_set(strings: Vec<String>, fixed: usize) -> Option<Vec<String>> {
+    // The first `fixed` arguments, such as the command name, key and value,
+    // are copied as they are; only the options after them are rewritten.
+    let mut iter = strings.into_iter();
+    let mut rewritten: Vec<String> = iter.by_ref().take(fixed).collect();
+    while let Some(arg) = iter.next() {
+        let unit_ms = match arg.to_lowercase().as_str() {
+            "px" => 1,
+            "ex" => 1000,
+            _ => {
+                rewritten.push(arg);
+                continue;
+            }
+        };
+        let ttl = iter.next()?.parse::<u64>().ok()?;
+        let at = unix_millis(SystemTime::now()).checked_add(ttl.checked_mul(unit_ms)?)?;
+        rewritten.push("PXAT".to_string());
+        rewritten.push(at.to_string());
+    }
+    Some(rewritten)
+}
+
+fn absolute_expire(strings: Vec<String>) -> Option<Vec<String>> {
+    let time = strings.get(2)?.parse::<i64>().ok()?;
+    let now = unix_millis(SystemTime::now()) as i64;
+    let at = match strings[0].to_lowercase().as_str() {
+        "expire" => now.checked_add(time.checked_mul(1000)?)?,
+        "pexpire" => now.checked_add(time)?,
+        _ => time.checked_mul(1000)?,
+    };
+    let mut rewritten = vec!["PEXPIREAT".to_string(), strings[1].clone(), at.to_string()];
+    rewritten.extend(strings.into_iter().skip(3));
+    Some(rewritten)
+}
+
+fn absolute_hexpire(strings: Vec<String>) -> Option<Vec<String>> {
+    let time = strings.get(2)?.parse::<u64>().ok()?;
+    let now = unix_millis(SystemTime::now());
+    let at = match strings[0].to_lowercase().as_str() {
+        "hexpire" => now.checked_add(time.checked_mul(1000)?)?,
+        "hpexpire" => now.checked_add(time)?,
+        _ => time.checked_mul(1000)?,
+    };
+    let mut iter = strings.into_iter().skip(1);
+    let mut rewritten = vec!["HPEXPIREAT".to_string(), iter.next()?, at.to_string()];
+    rewritten.extend(iter.skip(1));
+    Some(rewritten)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_skips_malformed_entries() {
+        let path = std::env::temp_dir().join(format!("aof-load-test-{}.aof", std::process::id()));
+        let mut log =
+            bulk_array(vec!["SET".to_string(), "a".to_string(), "1".to_string()]).encode();
+        log.extend(b"*0\r\n*1\r\n:1\r\n");
+        log.extend(bulk_array(vec!["SET".to_string(), "b".to_string(), "2".to_string()]).encode());
+        fs::write(&path, log).unwrap();
+
+        let store = Store::new(1);
+        let loaded = load(&path, &store);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), Some(2));
+        assert_eq!(store.read("a").unwrap(), Some("1".to_string()));
+        assert_eq!(store.read("b").unwrap(), Some("2".to_string()));
+    }
+}
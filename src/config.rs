@@ -0,0 +1,470 @@
+use anyhow::Result;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard},
+};
+
+use crate::{aof::FsyncPolicy, glob, rdb};
+
+static PORT: u16 = 6379;
+static REPL_BACKLOG_SIZE: u64 = 1024 * 1024;
+static HZ: u64 = 10;
+static DATABASES: usize = 16;
+/// The range Redis clamps `hz` to.
+const HZ_MIN: u64 = 1;
+const HZ_MAX: u64 = 500;
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// A redis.conf style config file. Flags given on the command line take
+    /// precedence over its directives.
+    pub config_file: Option<PathBuf>,
+    #[arg(short, long, default_value_t = PORT)]
+    pub port: u16,
+    #[arg(short, long, num_args = 1)]
+    pub replicaof: Option<Vec<String>>,
+    #[arg(long, default_value_t = REPL_BACKLOG_SIZE, value_parser = parse_memory)]
+    pub repl_backlog_size: u64,
+    #[arg(long, default_value = ".")]
+    pub dir: String,
+    #[arg(long, default_value = "dump.rdb")]
+    pub dbfilename: String,
+    /// Save policies as `<seconds> <changes>` pairs, e.g. `--save "3600 1 300 100"`.
+    #[arg(long, num_args = 1)]
+    pub save: Option<Vec<String>>,
+    /// Whether to log every write to the append only file (`yes` or `no`).
+    #[arg(long, default_value = "no")]
+    pub appendonly: String,
+    #[arg(long, default_value = "appendonly.aof")]
+    pub appendfilename: String,
+    /// When to fsync the append only file: `always`, `everysec` or `no`.
+    #[arg(long, default_value = "everysec")]
+    pub appendfsync: FsyncPolicy,
+    /// Memory limit in bytes, accepts units such as `100mb`. 0 means no limit.
+    #[arg(long, default_value_t = 0, value_parser = parse_memory)]
+    pub maxmemory: u64,
+    /// How many times per second background tasks such as expiring keys run.
+    #[arg(long, default_value_t = HZ, value_parser = parse_hz)]
+    pub hz: u64,
+    /// Number of databases clients can `SELECT`.
+    #[arg(long, default_value_t = DATABASES, value_parser = parse_databases)]
+    pub databases: usize,
+}
+
+/// Parameters exposed through `CONFIG GET` and `CONFIG SET`, in the order
+/// they are listed and rewritten.
+const PARAMETERS: &[&str] = &[
+    "port",
+    "replicaof",
+    "repl-backlog-size",
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "maxmemory",
+    "hz",
+    "databases",
+];
+
+/// Parameters that are only read at startup and cannot be changed with `CONFIG SET`.
+const IMMUTABLE: &[&str] = &[
+    "port",
+    "replicaof",
+    "appendonly",
+    "appendfilename",
+    "databases",
+];
+
+/// The server configuration, shared by all client threads so that changes
+/// made with `CONFIG SET` are seen everywhere.
+#[derive(Clone)]
+pub struct Config {
+    args: Arc<RwLock<Args>>,
+    /// The config file `CONFIG REWRITE` writes to, if the server was started with one.
+    file: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn new(args: Args) -> Self {
+        let file = args
+            .config_file
+            .as_ref()
+            .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+        Config {
+            args: Arc::new(RwLock::new(args)),
+            file,
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Args> {
+        self.args.read().unwrap()
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        let args = self.read();
+        Path::new(&args.dir).join(&args.dbfilename)
+    }
+
+    /// Returns the parameters matching any of the glob `patterns` with their values.
+    pub fn get(&self, patterns: &[String]) -> Vec<(String, String)> {
+        let args = self.read();
+        PARAMETERS
+            .iter()
+            .filter(|name| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob::matches(&pattern.to_lowercase(), name))
+            })
+            .map(|name| (name.to_string(), get_parameter(&args, name)))
+            .collect()
+    }
+
+    /// Changes parameters at runtime, given as name/value pairs. Either all
+    /// of them are applied or, if one is invalid, none.
+    pub fn set(&self, pairs: &[String]) -> Result<()> {
+        let mut args = self.args.write().unwrap();
+        let mut updated = args.clone();
+        for pair in pairs.chunks(2) {
+            let name = pair[0].to_lowercase();
+            if !PARAMETERS.contains(&name.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "Unknown option or number of arguments for CONFIG SET - '{name}'"
+                ));
+            }
+            if IMMUTABLE.contains(&name.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "CONFIG SET failed (possibly related to argument '{name}') - can't set immutable config"
+                ));
+            }
+            set_parameter(&mut updated, &name, &pair[1]).map_err(|e| {
+                anyhow::anyhow!("CONFIG SET failed (possibly related to argument '{name}') - {e}")
+            })?;
+        }
+        *args = updated;
+        Ok(())
+    }
+
+    /// Writes the current configuration back to the config file, keeping its
+    /// comments and layout and only touching the directives that changed.
+    pub fn rewrite(&self) -> Result<()> {
+        let path = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The server is running without a config file"))?;
+        let existing = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let args = self.read();
+        let defaults = Args::parse_from(["redis-server"]);
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut written: Vec<&str> = Vec::new();
+        for line in existing.lines() {
+            let directive = match line.split_whitespace().next() {
+                Some(word) if word.eq_ignore_ascii_case("slaveof") => "replicaof".to_string(),
+                word => word.unwrap_or("").to_lowercase(),
+            };
+            match PARAMETERS.iter().find(|name| **name == directive) {
+                Some(name) if written.contains(name) => {}
+                Some(name) => {
+                    lines.push(format_directive(name, &get_parameter(&args, name)));
+                    written.push(name);
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        for name in PARAMETERS {
+            let value = get_parameter(&args, name);
+            if !written.contains(name) && value != get_parameter(&defaults, name) {
+                lines.push(format_directive(name, &value));
+            }
+        }
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, lines.join("\n") + "\n")?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Parses the command line and fills in the settings that were not given
+/// there from the config file, if one was passed.
+pub fn load_args() -> Result<Args> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    let path = match args.config_file.clone() {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| anyhow::anyhow!("can't open config file {}: {}", path.display(), e))?;
+
+    // Directives that can be repeated accumulate, but only within the file:
+    // the first occurrence replaces the default.
+    let mut repeated: Vec<&str> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let words = split_line(line)
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), number + 1, e))?;
+        let (directive, values) = match words.split_first() {
+            Some((directive, values)) if !directive.starts_with('#') => (directive, values),
+            _ => continue,
+        };
+        let name = match directive.to_lowercase().as_str() {
+            "slaveof" => "replicaof".to_string(),
+            name => name.to_string(),
+        };
+        let param = match PARAMETERS.iter().find(|param| **param == name) {
+            Some(param) => *param,
+            None => {
+                println!(
+                    "{}:{}: ignoring unsupported directive '{}'",
+                    path.display(),
+                    number + 1,
+                    directive
+                );
+                continue;
+            }
+        };
+        if matches.value_source(&param.replace('-', "_")) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let value = values.join(" ");
+        let result = match param {
+            "save" => rdb::parse_save_policies(std::slice::from_ref(&value)).map(|_| {
+                if !repeated.contains(&param) {
+                    args.save = None;
+                    repeated.push(param);
+                }
+                args.save.get_or_insert_with(Vec::new).push(value);
+            }),
+            "replicaof" => {
+                if !repeated.contains(&param) {
+                    args.replicaof = None;
+                    repeated.push(param);
+                }
+                args.replicaof.get_or_insert_with(Vec::new).push(value);
+                Ok(())
+            }
+            _ => apply_startup_parameter(&mut args, param, &value),
+        };
+        result.map_err(|e| {
+            anyhow::anyhow!(
+                "{}:{}: '{}': {}",
+                path.display(),
+                number + 1,
+                line.trim(),
+                e
+            )
+        })?;
+    }
+    Ok(args)
+}
+
+/// Like `set_parameter`, but also accepts the parameters that can only be set at startup.
+fn apply_startup_parameter(args: &mut Args, name: &str, value: &str) -> Result<()> {
+    match name {
+        "port" => args.port = value.parse().map_err(|_| anyhow::anyhow!("invalid port"))?,
+        "appendonly" => match value.to_lowercase().as_str() {
+            "yes" | "no" => args.appendonly = value.to_lowercase(),
+            _ => return Err(anyhow::anyhow!("argument must be 'yes' or 'no'")),
+        },
+        "appendfilename" => args.appendfilename = value.to_string(),
+        "databases" => args.databases = parse_databases(value)?,
+        _ => set_parameter(args, name, value)?,
+    }
+    Ok(())
+}
+
+/// Splits a config file line into words the way Redis does, honouring
+/// double quotes with escapes and single quotes.
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        match c {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('r') => word.push('\r'),
+                            Some('t') => word.push('\t'),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| anyhow::anyhow!("invalid escape \\x{hex}"))?;
+                                word.push(byte as char);
+                            }
+                            Some(c) => word.push(c),
+                            None => return Err(anyhow::anyhow!("unbalanced quotes")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow::anyhow!("unbalanced quotes")),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            word.push('\'');
+                        }
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow::anyhow!("unbalanced quotes")),
+                    }
+                }
+            }
+            _ => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        if matches!(chars.peek(), Some(c) if !c.is_whitespace()) {
+            return Err(anyhow::anyhow!("closing quote must be followed by a space"));
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn get_parameter(args: &Args, name: &str) -> String {
+    match name {
+        "port" => args.port.to_string(),
+        "replicaof" => args
+            .replicaof
+            .as_ref()
+            .and_then(|addrs| addrs.first())
+            .cloned()
+            .unwrap_or_default(),
+        "repl-backlog-size" => args.repl_backlog_size.to_string(),
+        "dir" => args.dir.clone(),
+        "dbfilename" => args.dbfilename.clone(),
+        "save" => args.save.as_ref().map(|s| s.join(" ")).unwrap_or_default(),
+        "appendonly" => args.appendonly.clone(),
+        "appendfilename" => args.appendfilename.clone(),
+        "appendfsync" => match args.appendfsync {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+        .to_string(),
+        "maxmemory" => args.maxmemory.to_string(),
+        "hz" => args.hz.to_string(),
+        "databases" => args.databases.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn set_parameter(args: &mut Args, name: &str, value: &str) -> Result<()> {
+    match name {
+        "repl-backlog-size" => args.repl_backlog_size = parse_memory(value)?,
+        "dir" => {
+            // The append only file is opened at startup and would stay behind
+            // in the old directory.
+            if args.appendonly == "yes" {
+                return Err(anyhow::anyhow!(
+                    "dir can't be changed while appendonly is enabled"
+                ));
+            }
+            if !Path::new(value).is_dir() {
+                return Err(anyhow::anyhow!("No such file or directory"));
+            }
+            args.dir = value.to_string();
+        }
+        "dbfilename" => {
+            if value.contains('/') {
+                return Err(anyhow::anyhow!(
+                    "dbfilename can't be a path, just a filename"
+                ));
+            }
+            args.dbfilename = value.to_string();
+        }
+        "save" => {
+            rdb::parse_save_policies(&[value.to_string()])?;
+            args.save = Some(vec![value.to_string()]);
+        }
+        "appendfsync" => args.appendfsync = value.parse()?,
+        "maxmemory" => args.maxmemory = parse_memory(value)?,
+        "hz" => args.hz = parse_hz(value)?,
+        _ => return Err(anyhow::anyhow!("unsupported parameter")),
+    }
+    Ok(())
+}
+
+/// Formats a directive for the config file. `save` and `replicaof` take
+/// several arguments and are written as they are, other values are quoted
+/// when they contain spaces or are empty.
+fn format_directive(name: &str, value: &str) -> String {
+    let needs_quotes = value.is_empty() || value.contains(char::is_whitespace);
+    if matches!(name, "save" | "replicaof") || !needs_quotes {
+        format!("{name} {value}")
+    } else {
+        format!(
+            "{name} \"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    }
+}
+
+/// Parses `hz`, clamping it to the supported range like Redis does.
+fn parse_hz(s: &str) -> Result<u64> {
+    let hz = s
+        .parse::<u64>()
+        .map_err(|_| anyhow::anyhow!("argument couldn't be parsed into an integer"))?;
+    Ok(hz.clamp(HZ_MIN, HZ_MAX))
+}
+
+fn parse_databases(s: &str) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(databases) if databases > 0 => Ok(databases),
+        _ => Err(anyhow::anyhow!("argument must be a positive number")),
+    }
+}
+
+/// Parses a memory amount such as `1024`, `100kb` or `2gb` into bytes. Like
+/// Redis, `k`, `m` and `g` are powers of 1000 and `kb`, `mb` and `gb` powers of 1024.
+pub fn parse_memory(s: &str) -> Result<u64> {
+    let lower = s.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow::anyhow!("argument must be a memory value")),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow::anyhow!("argument must be a memory value"))
+}
@@ -1,46 +1,1609 @@
 use anyhow::Result;
+use resp::Value;
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    hash::{DefaultHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-struct RedisValue {
-    value: String,
-    expiry: Option<SystemTime>,
+use crate::{config::Config, error::CommandError, random_index};
+
+/// Keys with an expiry looked at in each round of the active expire cycle.
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+/// The share of each cycle, in percent, the active expire cycle may take up.
+const ACTIVE_EXPIRE_CYCLE_PERCENT: u32 = 25;
+/// How often a blocked client checks that it is still connected.
+const BLOCKED_CLIENT_CHECK: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub enum Data {
+    String(String),
+    List(VecDeque<String>),
+    Hash(Hash),
+    Set(ScanSet),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Data {
+    /// The type name `TYPE` replies with.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::String(_) => "string",
+            Data::List(_) => "list",
+            Data::Hash(_) => "hash",
+            Data::Set(_) => "set",
+            Data::SortedSet(_) => "zset",
+            Data::Stream(_) => "stream",
+        }
+    }
 }
 
+/// A hash field, which can expire on its own like a key.
+#[derive(Clone)]
+pub struct HashField {
+    pub value: String,
+    pub expiry: Option<SystemTime>,
+}
+
+impl HashField {
+    pub fn new(value: String) -> Self {
+        HashField {
+            value,
+            expiry: None,
+        }
+    }
+}
+
+/// The fields of a hash. Their expiries are also kept in order, so that
+/// finding the expired fields doesn't mean looking at all the others.
+#[derive(Clone, Default)]
+pub struct Hash {
+    fields: ScanMap<HashField>,
+    expiries: BTreeSet<(SystemTime, String)>,
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HashField> {
+        self.fields.get(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.fields.contains_key(name)
+    }
+
+    pub fn insert(&mut self, name: String, field: HashField) -> Option<HashField> {
+        if let Some(expiry) = field.expiry {
+            self.expiries.insert((expiry, name.clone()));
+        }
+        let old = self.fields.insert(name.clone(), field)?;
+        self.forget_expiry(name, &old);
+        Some(old)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<HashField> {
+        let field = self.fields.remove(name)?;
+        self.forget_expiry(name.to_string(), &field);
+        Some(field)
+    }
+
+    /// Sets the value of a field, which keeps its expiry if it exists.
+    pub fn set_value(&mut self, name: &str, value: String) {
+        match self.fields.get_mut(name) {
+            Some(field) => field.value = value,
+            None => {
+                self.fields.insert(name.to_string(), HashField::new(value));
+            }
+        }
+    }
+
+    /// Sets or clears the expiry of an existing field and returns the
+    /// previous one.
+    pub fn set_expiry(&mut self, name: &str, expiry: Option<SystemTime>) -> Option<SystemTime> {
+        let field = self.fields.get_mut(name)?;
+        let previous = std::mem::replace(&mut field.expiry, expiry);
+        if let Some(previous) = previous {
+            self.expiries.remove(&(previous, name.to_string()));
+        }
+        if let Some(expiry) = expiry {
+            self.expiries.insert((expiry, name.to_string()));
+        }
+        previous
+    }
+
+    /// The earliest expiry of any field.
+    pub fn min_expiry(&self) -> Option<SystemTime> {
+        self.expiries.first().map(|(expiry, _)| *expiry)
+    }
+
+    /// Removes the fields whose expiry has passed and returns how many there were.
+    pub fn remove_expired(&mut self, now: SystemTime) -> usize {
+        let mut removed = 0;
+        while self.min_expiry().is_some_and(|expiry| expiry < now) {
+            let (_, name) = self.expiries.pop_first().unwrap();
+            self.fields.remove(&name);
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &HashField)> {
+        self.fields.iter()
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, &HashField)>) {
+        self.fields.scan(cursor, count)
+    }
+
+    fn forget_expiry(&mut self, name: String, field: &HashField) {
+        if let Some(expiry) = field.expiry {
+            self.expiries.remove(&(expiry, name));
+        }
+    }
+}
+
+impl FromIterator<(String, HashField)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (String, HashField)>>(iter: I) -> Self {
+        let mut hash = Hash::new();
+        for (name, field) in iter {
+            hash.insert(name, field);
+        }
+        hash
+    }
+}
+
+impl IntoIterator for Hash {
+    type Item = (String, HashField);
+    type IntoIter = std::collections::hash_map::IntoIter<String, HashField>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.into_iter()
+    }
+}
+
+/// A map from names that can also be walked in the order of a fixed hash of
+/// the names, which is what `SCAN` cursors point into. Unlike a position, a
+/// hash is not thrown off by names added or removed between calls, so a full
+/// iteration returns every name that is there for its whole duration.
+#[derive(Clone)]
+pub struct ScanMap<V> {
+    items: HashMap<String, V>,
+    /// The names, ordered by their hash.
+    order: BTreeSet<(u64, String)>,
+}
+
+impl<V> Default for ScanMap<V> {
+    fn default() -> Self {
+        ScanMap {
+            items: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+}
+
+impl<V> ScanMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&V> {
+        self.items.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut V> {
+        self.items.get_mut(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.items.contains_key(name)
+    }
+
+    pub fn insert(&mut self, name: String, value: V) -> Option<V> {
+        if !self.items.contains_key(&name) {
+            self.order.insert((scan_hash(&name), name.clone()));
+        }
+        self.items.insert(name, value)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<V> {
+        let (name, value) = self.items.remove_entry(name)?;
+        self.order.remove(&(scan_hash(&name), name));
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.order.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.items.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.items.keys()
+    }
+
+    /// Returns the names in scan order, starting at the first one whose hash
+    /// is at least `start` and wrapping around to the beginning.
+    pub fn keys_from(&self, start: u64) -> impl Iterator<Item = &String> {
+        let (after, before) = (
+            self.order.range((start, String::new())..),
+            self.order.range(..(start, String::new())),
+        );
+        after.chain(before).map(|(_, name)| name)
+    }
+
+    /// Returns about `count` items starting at `cursor`, and the cursor to
+    /// continue from, which is 0 once the iteration is complete. Items whose
+    /// names hash the same go on one page, as the cursor can't point between
+    /// them.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, &V)>) {
+        let mut page = Vec::new();
+        let mut last = None;
+        for (hash, name) in self.order.range((cursor, String::new())..) {
+            if page.len() >= count && last != Some(*hash) {
+                return (*hash, page);
+            }
+            page.push((name, &self.items[name]));
+            last = Some(*hash);
+        }
+        (0, page)
+    }
+}
+
+impl<V> FromIterator<(String, V)> for ScanMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut map = ScanMap::new();
+        for (name, value) in iter {
+            map.insert(name, value);
+        }
+        map
+    }
+}
+
+impl<V> IntoIterator for ScanMap<V> {
+    type Item = (String, V);
+    type IntoIter = std::collections::hash_map::IntoIter<String, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a, V> IntoIterator for &'a ScanMap<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = std::collections::hash_map::Iter<'a, String, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+/// A set of names that `SSCAN` can walk like a [`ScanMap`].
+#[derive(Clone, Default)]
+pub struct ScanSet(ScanMap<()>);
+
+impl ScanSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        self.0.contains_key(member)
+    }
+
+    /// Adds `member`, returning false if it was already there.
+    pub fn insert(&mut self, member: String) -> bool {
+        self.0.insert(member, ()).is_none()
+    }
+
+    /// Removes `member`, returning false if it was not there.
+    pub fn remove(&mut self, member: &str) -> bool {
+        self.0.remove(member).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    /// Like [`ScanMap::keys_from`].
+    pub fn iter_from(&self, start: u64) -> impl Iterator<Item = &String> {
+        self.0.keys_from(start)
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&String>) {
+        let (next, page) = self.0.scan(cursor, count);
+        (next, page.into_iter().map(|(member, _)| member).collect())
+    }
+}
+
+impl FromIterator<String> for ScanSet {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        ScanSet(iter.into_iter().map(|member| (member, ())).collect())
+    }
+}
+
+impl IntoIterator for ScanSet {
+    type Item = String;
+    type IntoIter = std::iter::Map<
+        std::collections::hash_map::IntoIter<String, ()>,
+        fn((String, ())) -> String,
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter().map(|(member, _)| member)
+    }
+}
+
+impl<'a> IntoIterator for &'a ScanSet {
+    type Item = &'a String;
+    type IntoIter = std::collections::hash_map::Keys<'a, String, ()>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.items.keys()
+    }
+}
+
+/// A hash that stays the same for the lifetime of the server, unlike the
+/// randomly seeded one of `HashMap`.
+fn scan_hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(name.as_bytes());
+    hasher.finish()
+}
+
+/// The ID of a stream entry: a millisecond timestamp and a sequence number
+/// for entries added within the same millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// How to trim a stream: down to a number of entries or by removing the
+/// entries older than an ID.
+pub enum StreamTrim {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    /// The ID of the last entry ever added, which new IDs have to exceed even
+    /// if that entry was trimmed since.
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+/// A consumer group: the last entry delivered to any of its consumers and
+/// the entries delivered but not acknowledged yet.
+#[derive(Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    /// The pending entries list.
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    /// When each consumer was last seen, in Unix milliseconds.
+    pub consumers: BTreeMap<String, u64>,
+}
+
+#[derive(Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// When the entry was last delivered, in Unix milliseconds.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+impl Stream {
+    /// Removes the oldest entries according to `trim`, but at most `limit`
+    /// of them. Returns the number of entries removed.
+    pub fn trim(&mut self, trim: &StreamTrim, limit: Option<usize>) -> usize {
+        let mut removed = 0;
+        while limit.is_none_or(|limit| removed < limit) {
+            let first = match self.entries.first_key_value() {
+                Some((&id, _)) => id,
+                None => break,
+            };
+            let excess = match trim {
+                StreamTrim::MaxLen(len) => self.entries.len() > *len,
+                StreamTrim::MinId(min) => first < *min,
+            };
+            if !excess {
+                break;
+            }
+            self.entries.remove(&first);
+            self.max_deleted_id = self.max_deleted_id.max(first);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+const SKIPLIST_MAX_LEVEL: usize = 32;
+/// Index of the header node, which holds no element.
+const SKIPLIST_HEAD: usize = 0;
+
+#[derive(Clone, Copy)]
+struct SkipLevel {
+    forward: Option<usize>,
+    /// Number of elements this link skips over, used to compute ranks.
+    span: usize,
+}
+
+#[derive(Clone)]
+struct SkipNode {
+    member: String,
+    score: f64,
+    levels: Vec<SkipLevel>,
+}
+
+impl SkipNode {
+    fn precedes(&self, score: f64, member: &str) -> bool {
+        self.score < score || (self.score == score && self.member.as_str() < member)
+    }
+}
+
+/// A sorted set: members ordered by score, then lexicographically. Like in
+/// Redis, a skiplist whose links know how many elements they skip gives
+/// O(log n) inserts, removals and rank lookups, and a map gives the score of
+/// a member in constant time. Nodes live in an arena and refer to each other
+/// by index.
+#[derive(Clone)]
+pub struct SortedSet {
+    nodes: Vec<SkipNode>,
+    free: Vec<usize>,
+    scores: ScanMap<f64>,
+    level: usize,
+    /// State of the xorshift generator that picks the level of new nodes.
+    seed: u64,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        let head = SkipNode {
+            member: String::new(),
+            score: 0.0,
+            levels: vec![
+                SkipLevel {
+                    forward: None,
+                    span: 0,
+                };
+                SKIPLIST_MAX_LEVEL
+            ],
+        };
+        SortedSet {
+            nodes: vec![head],
+            free: Vec::new(),
+            scores: ScanMap::new(),
+            level: 1,
+            seed: crate::random_index(usize::MAX) as u64 | 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or updates its score. Returns true if it was added.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self.scores.get(&member).copied() {
+            Some(current) if current == score => false,
+            Some(current) => {
+                self.unlink(current, &member);
+                self.link(member.clone(), score);
+                self.scores.insert(member, score);
+                false
+            }
+            None => {
+                self.link(member.clone(), score);
+                self.scores.insert(member, score);
+                true
+            }
+        }
+    }
+
+    /// Removes `member`, returning true if it was present.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.unlink(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Zero based position of `member` in ascending order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.count_while(|entry| {
+            entry.score < score || (entry.score == score && entry.member < member)
+        }))
+    }
+
+    /// Number of elements, in ascending order, before the first one for which
+    /// `pred` is false. `pred` has to hold for a prefix of the set, like
+    /// "score is below 10".
+    pub fn count_while(&self, pred: impl Fn(&SortedEntry) -> bool) -> usize {
+        let mut x = SKIPLIST_HEAD;
+        let mut count = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !pred(&SortedEntry {
+                    member: &node.member,
+                    score: node.score,
+                }) {
+                    break;
+                }
+                count += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        count
+    }
+
+    /// Elements with a rank in `start..end`, in ascending order.
+    pub fn range(&self, start: usize, end: usize) -> Vec<(String, f64)> {
+        let mut elements = Vec::new();
+        let mut x = self.node_at(start);
+        while let Some(i) = x {
+            if elements.len() >= end.saturating_sub(start) {
+                break;
+            }
+            let node = &self.nodes[i];
+            elements.push((node.member.clone(), node.score));
+            x = node.levels[0].forward;
+        }
+        elements
+    }
+
+    /// Every element in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, f64)> + '_ {
+        let mut x = self.nodes[SKIPLIST_HEAD].levels[0].forward;
+        std::iter::from_fn(move || {
+            let node = &self.nodes[x?];
+            x = node.levels[0].forward;
+            Some((&node.member, node.score))
+        })
+    }
+
+    /// Returns about `count` elements starting at `cursor`, like [`ScanMap::scan`].
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, f64)>) {
+        let (next, page) = self.scores.scan(cursor, count);
+        let page = page
+            .into_iter()
+            .map(|(member, score)| (member, *score))
+            .collect();
+        (next, page)
+    }
+
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut x = SKIPLIST_HEAD;
+        let mut traversed = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// For every level, the last node before the position of `score` and
+    /// `member`, and the rank of that node.
+    fn predecessors(
+        &self,
+        score: f64,
+        member: &str,
+    ) -> ([usize; SKIPLIST_MAX_LEVEL], [usize; SKIPLIST_MAX_LEVEL]) {
+        let mut update = [SKIPLIST_HEAD; SKIPLIST_MAX_LEVEL];
+        let mut rank = [0; SKIPLIST_MAX_LEVEL];
+        let mut x = SKIPLIST_HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !self.nodes[next].precedes(score, member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    fn link(&mut self, member: String, score: f64) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = SKIPLIST_HEAD;
+                self.nodes[SKIPLIST_HEAD].levels[i].span = self.len();
+            }
+            self.level = level;
+        }
+
+        let node = SkipNode {
+            member,
+            score,
+            levels: vec![
+                SkipLevel {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let prev = self.nodes[update[i]].levels[i];
+            self.nodes[x].levels[i] = SkipLevel {
+                forward: prev.forward,
+                span: prev.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = SkipLevel {
+                forward: Some(x),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+    }
+
+    fn unlink(&mut self, score: f64, member: &str) {
+        let (update, _) = self.predecessors(score, member);
+        let x = match self.nodes[update[0]].levels[0].forward {
+            Some(x) if self.nodes[x].member == member => x,
+            _ => return,
+        };
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[x].levels.get(i).copied();
+            let prev = &mut self.nodes[prev].levels[i];
+            match removed {
+                Some(removed) if prev.forward == Some(x) => {
+                    prev.span = prev.span + removed.span - 1;
+                    prev.forward = removed.forward;
+                }
+                _ => prev.span -= 1,
+            }
+        }
+        while self.level > 1
+            && self.nodes[SKIPLIST_HEAD].levels[self.level - 1]
+                .forward
+                .is_none()
+        {
+            self.level -= 1;
+        }
+        self.nodes[x].member = String::new();
+        self.free.push(x);
+    }
+
+    /// Picks a level for a new node, each level being four times less likely
+    /// than the previous one.
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < SKIPLIST_MAX_LEVEL {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            if self.seed & 3 != 0 {
+                break;
+            }
+            level += 1;
+        }
+        level
+    }
+}
+
+/// An element of a [`SortedSet`], as seen by [`SortedSet::count_while`].
+pub struct SortedEntry<'a> {
+    pub member: &'a str,
+    pub score: f64,
+}
+
+#[derive(Clone)]
+pub struct RedisValue {
+    pub value: Data,
+    /// Once the value is in a [`Keyspace`], this is changed through
+    /// [`Keyspace::set_expiry`], which keeps track of the keys with an expiry.
+    pub expiry: Option<SystemTime>,
+}
+
+impl RedisValue {
+    pub fn new(value: Data) -> Self {
+        RedisValue {
+            value,
+            expiry: None,
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expiry, Some(expiry) if expiry < now)
+    }
+}
+
+/// The keys of the store. Expired keys are removed lazily when they are looked up.
+pub struct Keyspace {
+    entries: ScanMap<RedisValue>,
+    /// The keys that have an expiry, like Redis' `expires` dict, so that the
+    /// active expire cycle samples only those.
+    volatile: ScanSet,
+    /// Clients blocked on each key, in the order they started waiting.
+    waiters: HashMap<String, VecDeque<u64>>,
+    next_waiter: u64,
+    /// Number of keys removed because their time to live passed.
+    expired_keys: u64,
+}
+
+impl Keyspace {
+    pub fn get(&mut self, key: &str) -> Option<&mut RedisValue> {
+        let now = SystemTime::now();
+        let expired = match self.entries.get_mut(key) {
+            Some(data) if data.is_expired(now) => {
+                self.expired_keys += 1;
+                true
+            }
+            // Hash fields expire on their own, and a hash goes away with its
+            // last field.
+            Some(RedisValue {
+                value: Data::Hash(hash),
+                ..
+            }) => hash.remove_expired(now) > 0 && hash.is_empty(),
+            _ => false,
+        };
+        if expired {
+            self.remove_entry(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: RedisValue) {
+        if value.expiry.is_some() {
+            self.volatile.insert(key.clone());
+        } else {
+            self.volatile.remove(&key);
+        }
+        self.entries.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<RedisValue> {
+        self.get(key)?;
+        self.remove_entry(key)
+    }
+
+    /// Sets or clears the expiry of `key` and returns the previous one.
+    /// Does nothing if there is no such key.
+    pub fn set_expiry(&mut self, key: &str, expiry: Option<SystemTime>) -> Option<SystemTime> {
+        let data = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut data.expiry, expiry);
+        if expiry.is_some() {
+            self.volatile.insert(key.to_string());
+        } else {
+            self.volatile.remove(key);
+        }
+        previous
+    }
+
+    /// Removes every key.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.volatile = ScanSet::new();
+    }
+
+    /// Removes `key` whether or not it has expired.
+    fn remove_entry(&mut self, key: &str) -> Option<RedisValue> {
+        self.volatile.remove(key);
+        self.entries.remove(key)
+    }
+
+    /// Removes the expired keys among up to `count` keys with an expiry,
+    /// starting from a random position. Returns how many keys were looked at
+    /// and how many of them had expired.
+    fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        if self.volatile.is_empty() {
+            return (0, 0);
+        }
+        let now = SystemTime::now();
+        let start = random_index(usize::MAX) as u64;
+        let sample: Vec<(String, bool)> = self
+            .volatile
+            .iter_from(start)
+            .take(count)
+            .map(|key| {
+                (
+                    key.clone(),
+                    self.entries
+                        .get(key)
+                        .is_some_and(|data| data.is_expired(now)),
+                )
+            })
+            .collect();
+        let mut expired = 0;
+        for (key, _) in sample.iter().filter(|(_, is_expired)| *is_expired) {
+            self.remove_entry(key);
+            self.expired_keys += 1;
+            expired += 1;
+        }
+        (sample.len(), expired)
+    }
+
+    /// Returns the keys that have not expired with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &RedisValue)> {
+        let now = SystemTime::now();
+        self.entries
+            .iter()
+            .filter(move |(_, data)| !data.is_expired(now))
+    }
+
+    /// Returns the keys that have not expired.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Returns about `count` keys starting at `cursor`, like
+    /// [`ScanMap::scan`], leaving out the ones that have expired.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, &RedisValue)>) {
+        let now = SystemTime::now();
+        let (next, mut page) = self.entries.scan(cursor, count);
+        page.retain(|(_, data)| !data.is_expired(now));
+        (next, page)
+    }
+
+    pub fn string(&mut self, key: &str) -> Result<Option<&mut String>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::String(value),
+                ..
+            }) => Ok(Some(value)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn list(&mut self, key: &str) -> Result<Option<&mut VecDeque<String>>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::List(list),
+                ..
+            }) => Ok(Some(list)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Returns the list at `key`, creating an empty one if the key does not exist.
+    pub fn list_or_create(&mut self, key: &str) -> Result<&mut VecDeque<String>, CommandError> {
+        if self.get(key).is_none() {
+            self.insert(
+                key.to_string(),
+                RedisValue::new(Data::List(VecDeque::new())),
+            );
+        }
+        Ok(self.list(key)?.unwrap())
+    }
+
+    /// Returns the hash at `key` without its expired fields.
+    pub fn hash(&mut self, key: &str) -> Result<Option<&mut Hash>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::Hash(hash),
+                ..
+            }) => Ok(Some(hash)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Returns the hash at `key`, creating an empty one if the key does not exist.
+    pub fn hash_or_create(&mut self, key: &str) -> Result<&mut Hash, CommandError> {
+        if self.hash(key)?.is_none() {
+            self.insert(key.to_string(), RedisValue::new(Data::Hash(Hash::new())));
+        }
+        Ok(self.hash(key)?.unwrap())
+    }
+
+    pub fn set(&mut self, key: &str) -> Result<Option<&mut ScanSet>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::Set(set),
+                ..
+            }) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Returns the set at `key`, creating an empty one if the key does not exist.
+    pub fn set_or_create(&mut self, key: &str) -> Result<&mut ScanSet, CommandError> {
+        if self.get(key).is_none() {
+            self.insert(key.to_string(), RedisValue::new(Data::Set(ScanSet::new())));
+        }
+        Ok(self.set(key)?.unwrap())
+    }
+
+    pub fn sorted_set(&mut self, key: &str) -> Result<Option<&mut SortedSet>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::SortedSet(set),
+                ..
+            }) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Returns the sorted set at `key`, creating an empty one if the key does not exist.
+    pub fn sorted_set_or_create(&mut self, key: &str) -> Result<&mut SortedSet, CommandError> {
+        if self.get(key).is_none() {
+            self.insert(
+                key.to_string(),
+                RedisValue::new(Data::SortedSet(SortedSet::new())),
+            );
+        }
+        Ok(self.sorted_set(key)?.unwrap())
+    }
+
+    pub fn stream(&mut self, key: &str) -> Result<Option<&mut Stream>, CommandError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::Stream(stream),
+                ..
+            }) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Returns the stream at `key`, creating an empty one if the key does not exist.
+    pub fn stream_or_create(&mut self, key: &str) -> Result<&mut Stream, CommandError> {
+        if self.get(key).is_none() {
+            self.insert(
+                key.to_string(),
+                RedisValue::new(Data::Stream(Stream::default())),
+            );
+        }
+        Ok(self.stream(key)?.unwrap())
+    }
+
+    /// Removes `key` if it holds an empty list or other collection, since
+    /// Redis never keeps empty aggregate values around. Streams are the
+    /// exception and stay, to remember their last ID.
+    pub fn remove_if_empty(&mut self, key: &str) {
+        let empty = match self.entries.get(key).map(|data| &data.value) {
+            Some(Data::List(list)) => list.is_empty(),
+            Some(Data::Hash(hash)) => hash.is_empty(),
+            Some(Data::Set(set)) => set.is_empty(),
+            Some(Data::SortedSet(set)) => set.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.remove_entry(key);
+        }
+    }
+}
+
+/// A numbered database: its keyspace and the condition blocked clients wait on.
+struct Database {
+    keyspace: Mutex<Keyspace>,
+    /// Signalled after writes so blocked clients can check their keys again.
+    modified: Condvar,
+}
+
+/// A handle on the databases of the server. Each client connection has its
+/// own, since the database commands work on is chosen per connection with
+/// `SELECT`.
 #[derive(Clone)]
 pub struct Store {
-    storage: Arc<Mutex<HashMap<String, RedisValue>>>,
+    databases: Arc<Vec<Database>>,
+    /// The selected database.
+    db: usize,
+    /// Number of modifications since startup, used to decide when to save.
+    dirty: Arc<AtomicU64>,
+    /// Held from applying a write until it has been logged, so that replicas
+    /// and the append only file get writes in the order they were applied.
+    /// It is always taken before any keyspace lock.
+    write_order: Arc<Mutex<()>>,
+    /// Propagates writes to replicas and the append only file, once the
+    /// server has set them up.
+    write_log: Arc<OnceLock<Box<WriteLog>>>,
 }
 
+/// Receives a write command and the database it was applied to.
+pub type WriteLog = dyn Fn(usize, &Value) + Send + Sync;
+
 impl Store {
-    pub fn new() -> Self {
+    pub fn new(databases: usize) -> Self {
+        let databases = (0..databases)
+            .map(|_| Database {
+                keyspace: Mutex::new(Keyspace {
+                    entries: ScanMap::new(),
+                    volatile: ScanSet::new(),
+                    waiters: HashMap::new(),
+                    next_waiter: 0,
+                    expired_keys: 0,
+                }),
+                modified: Condvar::new(),
+            })
+            .collect();
         Store {
-            storage: Arc::new(Mutex::new(HashMap::new())),
+            databases: Arc::new(databases),
+            db: 0,
+            dirty: Arc::new(AtomicU64::new(0)),
+            write_order: Arc::new(Mutex::new(())),
+            write_log: Arc::new(OnceLock::new()),
         }
     }
 
-    pub fn read(&self, key: &String) -> Result<String> {
-        let mut storage = self.storage.lock().unwrap();
-        match storage.get(key) {
-            None => Err(anyhow::anyhow!("Key not found")),
-            Some(data) => {
-                if let Some(expiry) = data.expiry {
-                    if expiry < SystemTime::now() {
-                        storage.remove(key);
-                        return Err(anyhow::anyhow!("Key expired"));
+    /// Sets where `log_writes` sends writes. Until then they are only counted.
+    pub fn set_write_log(&self, log: Box<WriteLog>) {
+        if self.write_log.set(log).is_err() {
+            panic!("the write log is already set");
+        }
+    }
+
+    /// Orders writes for as long as the guard is held. Hold it from applying
+    /// a write until `log_writes` is done with it.
+    pub fn order_writes(&self) -> MutexGuard<'_, ()> {
+        self.write_order.lock().unwrap()
+    }
+
+    /// Propagates writes applied to the selected database.
+    pub fn log_writes(&self, writes: &[Value]) {
+        if let Some(log) = self.write_log.get() {
+            for write in writes {
+                log(self.db, write);
+            }
+        }
+    }
+
+    /// The index of the selected database.
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    /// The number of databases.
+    pub fn databases(&self) -> usize {
+        self.databases.len()
+    }
+
+    pub fn select(&mut self, db: usize) {
+        self.db = db;
+    }
+
+    /// Parses a database index, which has to be one of the configured databases.
+    pub fn parse_db(&self, s: &str) -> Result<usize, CommandError> {
+        let db = s.parse::<i64>().map_err(|_| CommandError::NotInteger)?;
+        usize::try_from(db)
+            .ok()
+            .filter(|&db| db < self.databases.len())
+            .ok_or(CommandError::DbOutOfRange)
+    }
+
+    /// Locks the keyspace of the selected database, for commands that need
+    /// several operations to be atomic.
+    pub fn lock(&self) -> MutexGuard<'_, Keyspace> {
+        self.lock_db(self.db)
+    }
+
+    /// Locks the selected database and another one, always in the same order
+    /// so that two clients locking the same pair can't deadlock.
+    pub fn lock_with(&self, other: usize) -> (MutexGuard<'_, Keyspace>, MutexGuard<'_, Keyspace>) {
+        assert_ne!(self.db, other, "a database can only be locked once");
+        if self.db < other {
+            let selected = self.lock();
+            (selected, self.lock_db(other))
+        } else {
+            let other = self.lock_db(other);
+            (self.lock(), other)
+        }
+    }
+
+    fn lock_db(&self, db: usize) -> MutexGuard<'_, Keyspace> {
+        self.databases[db].keyspace.lock().unwrap()
+    }
+
+    /// Swaps the keys of two databases. Clients connected to one database
+    /// see the keys of the other from then on.
+    pub fn swap(&self, a: usize, b: usize) {
+        if a != b {
+            let (low, high) = (a.min(b), a.max(b));
+            let mut low = self.lock_db(low);
+            let mut high = self.lock_db(high);
+            std::mem::swap(&mut low.entries, &mut high.entries);
+            std::mem::swap(&mut low.volatile, &mut high.volatile);
+        }
+        self.wake_blocked();
+    }
+
+    /// Removes every key of the selected database.
+    pub fn flush(&self) {
+        self.lock().clear();
+    }
+
+    /// Removes every key of every database.
+    pub fn flush_all(&self) {
+        for db in 0..self.databases.len() {
+            self.lock_db(db).clear();
+        }
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Records that a write command modified the store.
+    pub fn mark_dirty(&self) {
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        (0..self.databases.len())
+            .map(|db| self.lock_db(db).expired_keys)
+            .sum()
+    }
+
+    /// Returns the number of keys and of keys with an expiry in each database.
+    pub fn key_counts(&self) -> Vec<(usize, usize)> {
+        (0..self.databases.len())
+            .map(|db| {
+                let keyspace = self.lock_db(db);
+                let keys = keyspace.keys().count();
+                let expires = keyspace
+                    .iter()
+                    .filter(|(_, data)| data.expiry.is_some())
+                    .count();
+                (keys, expires)
+            })
+            .collect()
+    }
+
+    /// Starts a thread that removes expired keys in the background, since
+    /// keys are otherwise only removed when they are looked up. Like in
+    /// Redis, it runs `hz` times per second, sampling keys with an expiry in
+    /// each database, and keeps going while more than a quarter of a sample
+    /// had expired and it has not used up its share of the cycle.
+    pub fn start_active_expire(&self, config: Config) {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            let period = Duration::from_millis(1000 / config.read().hz);
+            thread::sleep(period);
+            let budget = period * ACTIVE_EXPIRE_CYCLE_PERCENT / 100;
+            let start = Instant::now();
+            for db in 0..store.databases.len() {
+                loop {
+                    // The lock is taken for each round so clients get their turn.
+                    let (sampled, expired) = store.lock_db(db).expire_sample(ACTIVE_EXPIRE_SAMPLE);
+                    if expired * 4 <= sampled || start.elapsed() >= budget {
+                        break;
                     }
                 }
-                Ok(data.value.clone())
+            }
+        });
+    }
+
+    /// Wakes up blocked clients so they can check their keys again.
+    pub fn wake_blocked(&self) {
+        for database in self.databases.iter() {
+            database.modified.notify_all();
+        }
+    }
+
+    /// Blocks until `serve` returns a value for one of `keys` or the deadline
+    /// passes, in which case `None` is returned. `serve` is only called for
+    /// keys on which no other client has been waiting longer, so clients
+    /// blocked on the same key are served in FIFO order. Besides the value,
+    /// `serve` returns the writes to log for what it did. A client that
+    /// `connected` reports gone stops waiting as if the deadline passed.
+    pub fn block<T>(
+        &self,
+        keys: &[String],
+        deadline: Option<Instant>,
+        connected: &dyn Fn() -> bool,
+        mut serve: impl FnMut(&mut Keyspace, &str) -> Result<Option<(T, Vec<Value>)>, CommandError>,
+    ) -> Result<Option<T>, CommandError> {
+        let mut order = self.order_writes();
+        let mut keyspace = self.lock();
+        let id = keyspace.next_waiter;
+        keyspace.next_waiter += 1;
+        for key in keys {
+            keyspace
+                .waiters
+                .entry(key.clone())
+                .or_default()
+                .push_back(id);
+        }
+
+        let result = 'wait: loop {
+            for key in keys {
+                if keyspace.waiters[key].front() != Some(&id) {
+                    continue;
+                }
+                match serve(&mut keyspace, key) {
+                    Ok(None) => {}
+                    result => break 'wait result,
+                }
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) || !connected() {
+                break Ok(None);
+            }
+            drop(order);
+            (order, keyspace) = self.wait_modified(keyspace, deadline);
+        };
+
+        for key in keys {
+            if let Some(queue) = keyspace.waiters.get_mut(key) {
+                queue.retain(|&waiter| waiter != id);
+                if queue.is_empty() {
+                    keyspace.waiters.remove(key);
+                }
             }
         }
+        drop(keyspace);
+        // The clients queued behind this one may be able to proceed now.
+        self.databases[self.db].modified.notify_all();
+        self.log_served(result)
     }
 
-    pub fn write(&self, key: String, value: String, expiry: Option<SystemTime>) -> Result<()> {
-        let mut storage = self.storage.lock().unwrap();
-        storage.insert(key, RedisValue { value, expiry });
-        Ok(())
+    /// Blocks until `check` returns a value or the deadline passes, in which
+    /// case `None` is returned. Unlike with `block`, clients do not queue up,
+    /// which suits commands that read without consuming anything. A deadline
+    /// that already passed checks once without blocking. Besides the value,
+    /// `check` returns the writes to log for what it did. Like with `block`,
+    /// a client that is gone stops waiting.
+    pub fn wait<T>(
+        &self,
+        deadline: Option<Instant>,
+        connected: &dyn Fn() -> bool,
+        mut check: impl FnMut(&mut Keyspace) -> Result<Option<(T, Vec<Value>)>, CommandError>,
+    ) -> Result<Option<T>, CommandError> {
+        let mut order = self.order_writes();
+        let mut keyspace = self.lock();
+        let result = loop {
+            match check(&mut keyspace) {
+                Ok(None) => {}
+                result => break result,
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) || !connected() {
+                break Ok(None);
+            }
+            drop(order);
+            (order, keyspace) = self.wait_modified(keyspace, deadline);
+        };
+        drop(keyspace);
+        self.log_served(result)
+    }
+
+    /// Logs the writes of a client that `block` or `wait` served. This
+    /// happens with writes still ordered but the keyspace unlocked, as the
+    /// write log takes locks that are held while the keyspace is locked.
+    fn log_served<T>(
+        &self,
+        result: Result<Option<(T, Vec<Value>)>, CommandError>,
+    ) -> Result<Option<T>, CommandError> {
+        Ok(result?.map(|(value, writes)| {
+            if !writes.is_empty() {
+                self.mark_dirty();
+                self.log_writes(&writes);
+            }
+            value
+        }))
+    }
+
+    /// Waits for the next change to the store, but not past the deadline or
+    /// longer than it takes to check the client again. Writes are ordered
+    /// before the keyspace is locked, so the keyspace is released and both
+    /// are taken again in that order.
+    fn wait_modified<'a>(
+        &'a self,
+        keyspace: MutexGuard<'a, Keyspace>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, ()>, MutexGuard<'a, Keyspace>) {
+        let timeout = deadline.map_or(BLOCKED_CLIENT_CHECK, |deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .min(BLOCKED_CLIENT_CHECK)
+        });
+        let modified = &self.databases[self.db].modified;
+        let keyspace = modified.wait_timeout(keyspace, timeout).unwrap().0;
+        drop(keyspace);
+        (self.order_writes(), self.lock())
+    }
+    pub fn read(&self, key: &str) -> Result<Option<String>, CommandError> {
+        match self.lock().get(key) {
+            None => Ok(None),
+            Some(RedisValue {
+                value: Data::String(value),
+                ..
+            }) => Ok(Some(value.clone())),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn insert(&self, key: String, value: RedisValue) {
+        self.lock().insert(key, value);
+    }
+
+    /// Returns a copy of every key that has not expired yet, leaving out
+    /// expired hash fields as well, for each database.
+    pub fn snapshot(&self) -> Vec<Vec<(String, RedisValue)>> {
+        let now = SystemTime::now();
+        (0..self.databases.len())
+            .map(|db| {
+                self.lock_db(db)
+                    .iter()
+                    .filter_map(|(key, data)| {
+                        let mut data = data.clone();
+                        if let Data::Hash(hash) = &mut data.value {
+                            hash.remove_expired(now);
+                            if hash.is_empty() {
+                                return None;
+                            }
+                        }
+                        Some((key.clone(), data))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(set: &SortedSet) -> Vec<(String, f64)> {
+        set.iter()
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+
+    fn sorted_set(elements: &[(&str, f64)]) -> SortedSet {
+        let mut set = SortedSet::new();
+        for (member, score) in elements {
+            set.insert(member.to_string(), *score);
+        }
+        set
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let set = sorted_set(&[("c", 2.0), ("b", 1.0), ("a", 2.0), ("d", 1.0), ("e", -1.0)]);
+        let order: Vec<_> = set.iter().map(|(member, _)| member.as_str()).collect();
+        assert_eq!(order, ["e", "b", "d", "a", "c"]);
+        assert_eq!(set.len(), 5);
+        assert_eq!(set.score("a"), Some(2.0));
+        assert_eq!(set.score("z"), None);
+    }
+
+    #[test]
+    fn ranks_ties_lexicographically() {
+        let set = sorted_set(&[("b", 1.0), ("a", 1.0), ("c", 1.0), ("x", 0.0)]);
+        assert_eq!(set.rank("x"), Some(0));
+        assert_eq!(set.rank("a"), Some(1));
+        assert_eq!(set.rank("b"), Some(2));
+        assert_eq!(set.rank("c"), Some(3));
+        assert_eq!(set.rank("missing"), None);
+    }
+
+    #[test]
+    fn updates_scores_in_place() {
+        let mut set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        assert!(!set.insert("a".to_string(), 4.0));
+        assert!(!set.insert("b".to_string(), 2.0));
+        assert_eq!(set.len(), 3);
+        assert_eq!(
+            members(&set),
+            [
+                ("b".to_string(), 2.0),
+                ("c".to_string(), 3.0),
+                ("a".to_string(), 4.0)
+            ]
+        );
+        assert_eq!(set.rank("a"), Some(2));
+        // Moving onto an existing score sorts by member among the ties.
+        assert!(!set.insert("a".to_string(), 2.0));
+        assert_eq!(set.rank("a"), Some(0));
+        assert_eq!(set.rank("b"), Some(1));
+    }
+
+    #[test]
+    fn removes_members() {
+        let mut set = sorted_set(&[("a", 1.0), ("b", 1.0), ("c", 2.0)]);
+        assert!(set.remove("b"));
+        assert!(!set.remove("b"));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.rank("c"), Some(1));
+        assert!(set.remove("a"));
+        assert!(set.remove("c"));
+        assert!(set.is_empty());
+        assert_eq!(set.range(0, 10), []);
+        // Nodes freed by the removals are reused.
+        assert!(set.insert("d".to_string(), 0.0));
+        assert_eq!(members(&set), [("d".to_string(), 0.0)]);
+    }
+
+    #[test]
+    fn ranges_by_rank() {
+        let set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)]);
+        let names = |range: Vec<(String, f64)>| -> Vec<String> {
+            range.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(names(set.range(0, 4)), ["a", "b", "c", "d"]);
+        assert_eq!(names(set.range(1, 3)), ["b", "c"]);
+        assert_eq!(names(set.range(3, 100)), ["d"]);
+        assert!(set.range(4, 100).is_empty());
+        assert!(set.range(2, 2).is_empty());
+        assert_eq!(set.count_while(|entry| entry.score < 2.0), 1);
+        assert_eq!(set.count_while(|entry| entry.score <= 2.0), 3);
+    }
+
+    #[test]
+    fn matches_a_sorted_vec() {
+        let mut set = SortedSet::new();
+        let mut model: Vec<(String, f64)> = Vec::new();
+        // A fixed linear congruential sequence keeps the test reproducible.
+        let mut state: u64 = 42;
+        let mut next = |bound: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % bound
+        };
+        for _ in 0..2000 {
+            let member = format!("m{}", next(200));
+            let score = next(20) as f64;
+            model.retain(|(m, _)| *m != member);
+            if next(4) == 0 {
+                set.remove(&member);
+            } else {
+                set.insert(member.clone(), score);
+                model.push((member, score));
+            }
+        }
+        model.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+
+        assert_eq!(set.len(), model.len());
+        assert_eq!(members(&set), model);
+        for (rank, (member, score)) in model.iter().enumerate() {
+            assert_eq!(set.rank(member), Some(rank));
+            assert_eq!(set.score(member), Some(*score));
+        }
+        for start in (0..model.len()).step_by(7) {
+            assert_eq!(
+                set.range(start, start + 5),
+                model[start..(start + 5).min(model.len())]
+            );
+        }
+    }
+
+    #[test]
+    fn expire_sample_only_looks_at_keys_with_an_expiry() {
+        let store = Store::new(1);
+        let mut keyspace = store.lock();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        for i in 0..1000 {
+            keyspace.insert(
+                format!("key{i}"),
+                RedisValue::new(Data::String(String::new())),
+            );
+        }
+        for key in ["a", "b", "c"] {
+            let mut value = RedisValue::new(Data::String(String::new()));
+            value.expiry = Some(past);
+            keyspace.insert(key.to_string(), value);
+        }
+        keyspace.insert(
+            "persisted".to_string(),
+            RedisValue::new(Data::String(String::new())),
+        );
+        keyspace.set_expiry("persisted", Some(past));
+        keyspace.set_expiry("persisted", None);
+        keyspace.set_expiry("key0", Some(past));
+
+        assert_eq!(keyspace.expire_sample(20), (4, 4));
+        assert_eq!(keyspace.expire_sample(20), (0, 0));
+        assert_eq!(keyspace.entries.len(), 1000);
+        assert!(keyspace.entries.contains_key("persisted"));
+        assert!(!keyspace.entries.contains_key("key0"));
     }
 }
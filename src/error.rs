@@ -0,0 +1,119 @@
+use thiserror::Error;
+
+/// Errors caused by the client's command. `handle_client` sends them back as
+/// RESP errors instead of dropping the reply.
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(&'static str),
+    #[error("value is not an integer or out of range")]
+    NotInteger,
+    #[error("syntax error")]
+    Syntax,
+    #[error("no such key")]
+    NoSuchKey,
+    #[error("index out of range")]
+    IndexOutOfRange,
+    #[error("value is out of range, must be positive")]
+    NotPositive,
+    #[error("timeout is not a float or out of range")]
+    InvalidTimeout,
+    #[error("timeout is negative")]
+    NegativeTimeout,
+    #[error("timeout is out of range")]
+    TimeoutOutOfRange,
+    #[error("WAIT cannot be used with replica instances")]
+    WaitOnReplica,
+    #[error("hash value is not an integer")]
+    HashNotInteger,
+    #[error("increment or decrement would overflow")]
+    Overflow,
+    #[error("invalid expire time in '{0}' command")]
+    InvalidExpire(&'static str),
+    #[error("Mandatory argument FIELDS is missing or not at the right position")]
+    MissingFields,
+    #[error("The `numfields` parameter must match the number of arguments")]
+    NumFields,
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("value is not a valid float")]
+    NotFloat,
+    #[error("XX and NX options at the same time are not compatible")]
+    XxAndNx,
+    #[error("GT, LT, and/or NX options at the same time are not compatible")]
+    GtLtNx,
+    #[error("INCR option supports a single increment-element pair")]
+    IncrPair,
+    #[error("resulting score is not a number (NaN)")]
+    ScoreNan,
+    #[error("min or max is not a float")]
+    MinMaxNotFloat,
+    #[error("min or max not valid string range item")]
+    MinMaxNotLex,
+    #[error("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX")]
+    LimitWithoutBy,
+    #[error("syntax error, WITHSCORES not supported in combination with BYLEX")]
+    WithScoresByLex,
+    #[error("weight value is not a float")]
+    WeightNotFloat,
+    #[error("at least 1 input key is needed for '{0}' command")]
+    NoInputKeys(&'static str),
+    #[error("Invalid stream ID specified as stream command argument")]
+    InvalidStreamId,
+    #[error("The ID specified in XADD must be greater than 0-0")]
+    StreamIdZero,
+    #[error("The ID specified in XADD is equal or smaller than the target stream top item")]
+    StreamIdTooSmall,
+    #[error("The stream has exhausted the last possible ID, unable to add more items")]
+    StreamExhausted,
+    #[error("The MAXLEN argument must be >= 0.")]
+    NegativeMaxLen,
+    #[error("syntax error, LIMIT cannot be used without the special ~ option")]
+    LimitWithoutApprox,
+    #[error("timeout is not an integer or out of range")]
+    TimeoutNotInteger,
+    #[error(
+        "Unbalanced '{0}' list of streams: for each stream key an ID or '{1}' must be specified."
+    )]
+    UnbalancedStreams(&'static str, &'static str),
+    #[error("The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.")]
+    GreaterIdOutsideGroup,
+    #[error("The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.")]
+    LastIdInGroup,
+    #[error("NOGROUP No such key '{0}' or consumer group '{1}'")]
+    NoGroup(String, String),
+    #[error("NOGROUP No such key '{0}' or consumer group '{1}' in XREADGROUP with GROUP option")]
+    NoGroupToRead(String, String),
+    #[error("NOGROUP No such consumer group '{1}' for key name '{0}'")]
+    NoGroupForKey(String, String),
+    #[error("BUSYGROUP Consumer Group name already exists")]
+    BusyGroup,
+    #[error("The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.")]
+    GroupWithoutStream,
+    #[error("unknown subcommand or wrong number of arguments for '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, &'static str),
+    #[error("COUNT must be > 0")]
+    CountNotPositive,
+    #[error("increment would produce NaN or Infinity")]
+    NanOrInfinity,
+    #[error("Unsupported option {0}")]
+    UnsupportedOption(String),
+    #[error("NX and XX, GT or LT options at the same time are not compatible")]
+    NxAndOtherOptions,
+    #[error("GT and LT options at the same time are not compatible")]
+    GtAndLt,
+    #[error("invalid {0} DB index")]
+    InvalidDbIndex(&'static str),
+    #[error("DB index is out of range")]
+    DbOutOfRange,
+    #[error("source and destination objects are the same")]
+    SameObject,
+    #[error("offset is out of range")]
+    OffsetOutOfRange,
+    #[error("string exceeds maximum allowed size (proto-max-bulk-len)")]
+    StringTooLong,
+    #[error("range would split a multi-byte character")]
+    SplitsCharacter,
+}
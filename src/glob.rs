@@ -0,0 +1,156 @@
+/// Matches `s` against a Redis style glob pattern supporting `*`, `?`,
+/// `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+pub fn matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    matches_from(&pattern, &s)
+}
+
+/// Matches with two pointers: on a mismatch, the last `*` takes one more
+/// character and matching resumes right after it. Backtracking to an earlier
+/// `*` never helps, so this takes O(pattern * s) steps without recursing.
+fn matches_from(pattern: &[char], s: &[char]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The pattern index after the last `*` and the string index it matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, i));
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => {
+                let (matched, end) = match_class(pattern, p + 1, s[i]);
+                matched.then_some(end + 1)
+            }
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+            Some(&c) => (c == s[i]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+            (None, Some((star_p, star_i))) => {
+                star = Some((star_p, star_i + 1));
+                p = star_p;
+                i = star_i + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    // Only stars are left to match the end of the string.
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the character class starting at `start`, just after
+/// the `[`. Returns whether it matched and the index of the closing `]`.
+fn match_class(pattern: &[char], start: usize, c: char) -> (bool, usize) {
+    let mut p = start;
+    let negate = pattern.get(p) == Some(&'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (mut lo, mut hi) = (pattern[p], pattern[p + 2]);
+            if lo > hi {
+                std::mem::swap(&mut lo, &mut hi);
+            }
+            matched |= lo <= c && c <= hi;
+            p += 2;
+        } else {
+            matched |= pattern[p] == c;
+        }
+        p += 1;
+    }
+    // An unterminated class runs to the end of the pattern, like in Redis.
+    let end = p.min(pattern.len() - 1);
+    (matched != negate, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn literals_and_question_marks() {
+        assert!(matches("hello", "hello"));
+        assert!(!matches("hello", "hell"));
+        assert!(!matches("hell", "hello"));
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "héllo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("", ""));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn stars() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("h*o", "hello"));
+        assert!(matches("h*o", "ho"));
+        assert!(!matches("h*o", "hola"));
+        assert!(matches("*llo", "hello"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(matches("a*b*c", "abcbc"));
+        assert!(!matches("a*b*c", "acb"));
+        assert!(matches("**a**", "bab"));
+        assert!(matches("user:*:name", "user:1:x:name"));
+    }
+
+    #[test]
+    fn stars_do_not_backtrack_exponentially() {
+        let s = "a".repeat(1000);
+        let pattern = "a*".repeat(30) + "b";
+        assert!(!matches(&pattern, &s));
+        assert!(matches(&("a*".repeat(30) + "a"), &s));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(!matches("h[^e]llo", "hllo"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        // Reversed ranges work too, like in Redis.
+        assert!(matches("[c-a]x", "bx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("key[0-9][0-9]", "key42"));
+        assert!(matches("*[0-9]", "key7"));
+        assert!(!matches("*[0-9]", "key"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("\\?", "?"));
+        assert!(!matches("\\?", "x"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[\\^a]", "^"));
+        // A trailing backslash stands for itself.
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_class_runs_to_the_end() {
+        assert!(matches("[abc", "b"));
+        assert!(!matches("[abc", "d"));
+        // A trailing `[` opens an empty class, which matches nothing.
+        assert!(!matches("x[", "x["));
+    }
+}
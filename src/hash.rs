@@ -0,0 +1,348 @@
+use anyhow::Result;
+use resp::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    db::{HashField, Store},
+    error::CommandError,
+    keys::{scan_reply, ScanOptions},
+    parse_int, unpack_args,
+};
+
+/// The latest expiry a hash field can have, in Unix milliseconds, as in Redis.
+const MAX_FIELD_EXPIRY_MS: u64 = (1 << 48) - 1;
+
+/// `HSET key field value [field value ...]`
+pub fn handle_hset(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 || args.len() % 2 == 0 {
+        return Err(CommandError::WrongArity("hset").into());
+    }
+    let mut keyspace = store.lock();
+    let hash = keyspace.hash_or_create(&args[0])?;
+    let mut added = 0;
+    for pair in args[1..].chunks(2) {
+        // Overwriting a field also clears its expiry, like in Redis.
+        if hash
+            .insert(pair[0].clone(), HashField::new(pair[1].clone()))
+            .is_none()
+        {
+            added += 1;
+        }
+    }
+    Ok(Value::Integer(added))
+}
+
+/// `HGET key field`
+pub fn handle_hget(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("hget").into());
+    }
+    let mut keyspace = store.lock();
+    let value = keyspace
+        .hash(&args[0])?
+        .and_then(|hash| hash.get(&args[1]))
+        .map(|field| field.value.clone());
+    Ok(value.map(Value::Bulk).unwrap_or(Value::Null))
+}
+
+/// `HMGET key field [field ...]`
+pub fn handle_hmget(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("hmget").into());
+    }
+    let mut keyspace = store.lock();
+    let hash = keyspace.hash(&args[0])?;
+    let values = args[1..]
+        .iter()
+        .map(|name| {
+            hash.as_ref()
+                .and_then(|hash| hash.get(name))
+                .map(|field| Value::Bulk(field.value.clone()))
+                .unwrap_or(Value::Null)
+        })
+        .collect();
+    Ok(Value::Array(values))
+}
+
+/// `HDEL key field [field ...]`
+pub fn handle_hdel(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("hdel").into());
+    }
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let hash = match keyspace.hash(key)? {
+        Some(hash) => hash,
+        None => return Ok(Value::Integer(0)),
+    };
+    let removed = args[1..]
+        .iter()
+        .filter(|name| hash.remove(name).is_some())
+        .count();
+    keyspace.remove_if_empty(key);
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `HGETALL key`
+pub fn handle_hgetall(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("hgetall").into());
+    }
+    let mut keyspace = store.lock();
+    let pairs = match keyspace.hash(&args[0])? {
+        Some(hash) => hash
+            .iter()
+            .flat_map(|(name, field)| [Value::Bulk(name.clone()), Value::Bulk(field.value.clone())])
+            .collect(),
+        None => Vec::new(),
+    };
+    Ok(Value::Array(pairs))
+}
+
+/// `HLEN key`
+pub fn handle_hlen(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("hlen").into());
+    }
+    let len = store.lock().hash(&args[0])?.map_or(0, |hash| hash.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `HEXISTS key field`
+pub fn handle_hexists(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("hexists").into());
+    }
+    let exists = store
+        .lock()
+        .hash(&args[0])?
+        .is_some_and(|hash| hash.contains_key(&args[1]));
+    Ok(Value::Integer(exists as i64))
+}
+
+/// `HINCRBY key field increment`
+pub fn handle_hincrby(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("hincrby").into());
+    }
+    let increment = parse_int(&args[2])?;
+    let mut keyspace = store.lock();
+    let hash = keyspace.hash_or_create(&args[0])?;
+    let current = match hash.get(&args[1]) {
+        Some(field) => field
+            .value
+            .parse::<i64>()
+            .map_err(|_| CommandError::HashNotInteger)?,
+        None => 0,
+    };
+    let value = current
+        .checked_add(increment)
+        .ok_or(CommandError::Overflow)?;
+    // The field keeps its expiry, if it has one.
+    hash.set_value(&args[1], value.to_string());
+    Ok(Value::Integer(value))
+}
+
+/// `HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]`
+pub fn handle_hscan(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("hscan").into());
+    }
+    let options = ScanOptions::parse(&args[1], &args[2..], "novalues")?;
+    let mut keyspace = store.lock();
+    let hash = keyspace.hash(&args[0])?;
+    let (next, fields) = hash.map_or((0, Vec::new()), |hash| {
+        hash.scan(options.cursor, options.count)
+    });
+    let mut page = Vec::new();
+    for (name, field) in fields {
+        if !options.matches(name) {
+            continue;
+        }
+        page.push(Value::Bulk(name.clone()));
+        if options.flag.is_none() {
+            page.push(Value::Bulk(field.value.clone()));
+        }
+    }
+    Ok(scan_reply(next, page))
+}
+
+/// Condition under which `HEXPIRE`, `EXPIRE` and friends replace an expiry.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// Only if the field has no expiry.
+    Nx,
+    /// Only if the field already has an expiry.
+    Xx,
+    /// Only if the new expiry is later. A field without expiry never expires,
+    /// so this never matches it.
+    Gt,
+    /// Only if the new expiry is earlier.
+    Lt,
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "nx" => Some(Condition::Nx),
+            "xx" => Some(Condition::Xx),
+            "gt" => Some(Condition::Gt),
+            "lt" => Some(Condition::Lt),
+            _ => None,
+        }
+    }
+
+    pub fn allows(self, current: Option<SystemTime>, expiry: SystemTime) -> bool {
+        match self {
+            Condition::Nx => current.is_none(),
+            Condition::Xx => current.is_some(),
+            Condition::Gt => matches!(current, Some(current) if expiry > current),
+            Condition::Lt => !matches!(current, Some(current) if expiry >= current),
+        }
+    }
+}
+
+/// `HEXPIRE` / `HPEXPIRE` / `HEXPIREAT` / `HPEXPIREAT key time [NX | XX | GT | LT] FIELDS numfields field [field ...]`
+///
+/// Replies with one code per field: -2 if it does not exist, 0 if the
+/// condition was not met, 1 if the expiry was set and 2 if the field was
+/// deleted because the time is already in the past.
+pub fn handle_hexpire(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 5 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let time = parse_int(&args[1])?;
+    let now = SystemTime::now();
+    let now_ms = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let (unit_ms, base_ms) = match command {
+        "hexpire" => (1000, now_ms),
+        "hpexpire" => (1, now_ms),
+        "hexpireat" => (1000, 0),
+        _ => (1, 0),
+    };
+    let expiry_ms = u64::try_from(time)
+        .ok()
+        .and_then(|time| time.checked_mul(unit_ms))
+        .and_then(|ms| ms.checked_add(base_ms))
+        .filter(|&ms| ms <= MAX_FIELD_EXPIRY_MS)
+        .ok_or(CommandError::InvalidExpire(command))?;
+    let expiry = UNIX_EPOCH + Duration::from_millis(expiry_ms);
+    let condition = Condition::parse(&args[2]);
+    let fields = parse_fields(&args[2 + condition.is_some() as usize..])?;
+
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let hash = match keyspace.hash(key)? {
+        Some(hash) => hash,
+        None => return Ok(codes(fields.iter().map(|_| -2))),
+    };
+    let mut results = Vec::new();
+    for name in fields {
+        let current = match hash.get(name) {
+            Some(field) => field.expiry,
+            None => {
+                results.push(-2);
+                continue;
+            }
+        };
+        if condition.is_some_and(|condition| !condition.allows(current, expiry)) {
+            results.push(0);
+        } else if expiry <= now {
+            hash.remove(name);
+            results.push(2);
+        } else {
+            hash.set_expiry(name, Some(expiry));
+            results.push(1);
+        }
+    }
+    keyspace.remove_if_empty(key);
+    Ok(codes(results.into_iter()))
+}
+
+/// `HTTL` / `HPTTL key FIELDS numfields field [field ...]`
+///
+/// Replies with the remaining time to live of each field, -1 if it has no
+/// expiry or -2 if it does not exist.
+pub fn handle_httl(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 4 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let fields = parse_fields(&args[1..])?;
+    let mut keyspace = store.lock();
+    let hash = keyspace.hash(&args[0])?;
+    let now = SystemTime::now();
+    let ttls = fields
+        .iter()
+        .map(|name| match hash.as_ref().and_then(|hash| hash.get(name)) {
+            None => -2,
+            Some(HashField { expiry: None, .. }) => -1,
+            Some(HashField {
+                expiry: Some(expiry),
+                ..
+            }) => {
+                let ms = expiry
+                    .duration_since(now)
+                    .map(|ttl| ttl.as_millis() as i64)
+                    .unwrap_or(0);
+                if command == "hpttl" {
+                    ms
+                } else {
+                    (ms + 500) / 1000
+                }
+            }
+        });
+    Ok(codes(ttls))
+}
+
+/// `HPERSIST key FIELDS numfields field [field ...]`
+///
+/// Replies with 1 for each field whose expiry was removed, -1 if it had none
+/// or -2 if it does not exist.
+pub fn handle_hpersist(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 4 {
+        return Err(CommandError::WrongArity("hpersist").into());
+    }
+    let fields = parse_fields(&args[1..])?;
+    let mut keyspace = store.lock();
+    let mut hash = keyspace.hash(&args[0])?;
+    let results = fields.iter().map(|name| match hash.as_mut() {
+        Some(hash) if hash.contains_key(name) => match hash.set_expiry(name, None) {
+            Some(_) => 1,
+            None => -1,
+        },
+        _ => -2,
+    });
+    Ok(codes(results))
+}
+
+/// Parses `FIELDS numfields field [field ...]`, which must be the rest of the arguments.
+fn parse_fields(args: &[String]) -> Result<&[String], CommandError> {
+    if args.len() < 2 || !args[0].eq_ignore_ascii_case("fields") {
+        return Err(CommandError::MissingFields);
+    }
+    let fields = &args[2..];
+    let count = parse_int(&args[1])?;
+    if count <= 0 || count as usize != fields.len() {
+        return Err(CommandError::NumFields);
+    }
+    Ok(fields)
+}
+
+fn codes(codes: impl Iterator<Item = i64>) -> Value {
+    Value::Array(codes.map(Value::Integer).collect())
+}
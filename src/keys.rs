@@ -0,0 +1,436 @@
+use anyhow::Result;
+use resp::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    db::{Keyspace, Store},
+    error::CommandError,
+    glob,
+    hash::Condition,
+    parse_int, random_index, unpack_args,
+};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// `DEL` / `UNLINK key [key ...]`
+///
+/// Returns the number of keys removed. Values are always freed right away,
+/// so `UNLINK` is the same as `DEL`.
+pub fn handle_del(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let mut keyspace = store.lock();
+    let removed = args
+        .iter()
+        .filter(|key| keyspace.remove(key).is_some())
+        .count();
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `EXISTS` / `TOUCH key [key ...]`
+///
+/// Returns the number of keys that exist, counting repeated keys each time.
+pub fn handle_exists(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let mut keyspace = store.lock();
+    let existing = args
+        .iter()
+        .filter(|key| keyspace.get(key).is_some())
+        .count();
+    Ok(Value::Integer(existing as i64))
+}
+
+/// `TYPE key`
+pub fn handle_type(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("type").into());
+    }
+    let name = store
+        .lock()
+        .get(&args[0])
+        .map_or("none", |data| data.value.type_name());
+    Ok(Value::String(name.to_string()))
+}
+
+/// `RENAME key newkey` and `RENAMENX key newkey`, which only renames if
+/// `newkey` does not exist. The key keeps its time to live.
+pub fn handle_rename(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let (key, new_key) = (&args[0], &args[1]);
+    let nx = command == "renamenx";
+    let mut keyspace = store.lock();
+    if keyspace.get(key).is_none() {
+        return Err(CommandError::NoSuchKey.into());
+    }
+    if nx && keyspace.get(new_key).is_some() {
+        return Ok(Value::Integer(0));
+    }
+    if key != new_key {
+        let value = keyspace.remove(key).unwrap();
+        keyspace.insert(new_key.clone(), value);
+    }
+    Ok(if nx {
+        Value::Integer(1)
+    } else {
+        Value::String("OK".to_string())
+    })
+}
+
+/// `COPY source destination [DB destination-db] [REPLACE]`
+///
+/// The copy gets the time to live of the source. Returns 0 if the
+/// destination exists and `REPLACE` is not given.
+pub fn handle_copy(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("copy").into());
+    }
+    let (source, destination) = (&args[0], &args[1]);
+    let mut replace = false;
+    let mut db = store.db();
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "replace" => replace = true,
+            "db" => {
+                db = store.parse_db(args.get(i + 1).ok_or(CommandError::Syntax)?)?;
+                i += 1;
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+        i += 1;
+    }
+    if source == destination && db == store.db() {
+        return Err(CommandError::SameObject.into());
+    }
+
+    let (mut keyspace, mut other) = if db == store.db() {
+        (store.lock(), None)
+    } else {
+        let (keyspace, other) = store.lock_with(db);
+        (keyspace, Some(other))
+    };
+    let value = match keyspace.get(source) {
+        Some(value) => value.clone(),
+        None => return Ok(Value::Integer(0)),
+    };
+    let target = other.as_deref_mut().unwrap_or(&mut keyspace);
+    if !replace && target.get(destination).is_some() {
+        return Ok(Value::Integer(0));
+    }
+    target.insert(destination.clone(), value);
+    Ok(Value::Integer(1))
+}
+
+/// `MOVE key db`
+///
+/// Returns 0 if the key does not exist or already exists in `db`.
+pub fn handle_move(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("move").into());
+    }
+    let key = &args[0];
+    let db = store.parse_db(&args[1])?;
+    if db == store.db() {
+        return Err(CommandError::SameObject.into());
+    }
+    let (mut keyspace, mut other) = store.lock_with(db);
+    if keyspace.get(key).is_none() || other.get(key).is_some() {
+        return Ok(Value::Integer(0));
+    }
+    let value = keyspace.remove(key).unwrap();
+    other.insert(key.clone(), value);
+    Ok(Value::Integer(1))
+}
+
+/// `SELECT index`
+pub fn handle_select(args: Vec<Value>, store: &mut Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("select").into());
+    }
+    let db = store.parse_db(&args[0])?;
+    store.select(db);
+    Ok(Value::String("OK".to_string()))
+}
+
+/// `SWAPDB index1 index2`
+pub fn handle_swapdb(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("swapdb").into());
+    }
+    let parse = |s: &str, which| match store.parse_db(s) {
+        Err(CommandError::NotInteger) => Err(CommandError::InvalidDbIndex(which)),
+        result => result,
+    };
+    let a = parse(&args[0], "first")?;
+    let b = parse(&args[1], "second")?;
+    store.swap(a, b);
+    Ok(Value::String("OK".to_string()))
+}
+
+/// `FLUSHDB` / `FLUSHALL [ASYNC | SYNC]`
+///
+/// Memory is always freed right away, so `ASYNC` is the same as `SYNC`.
+pub fn handle_flush(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    match args.as_slice() {
+        [] => {}
+        [mode] if mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync") => {}
+        [_] => return Err(CommandError::Syntax.into()),
+        _ => return Err(CommandError::WrongArity(command).into()),
+    }
+    if command == "flushall" {
+        store.flush_all();
+    } else {
+        store.flush();
+    }
+    Ok(Value::String("OK".to_string()))
+}
+
+/// `DBSIZE`
+pub fn handle_dbsize(args: Vec<Value>, store: &Store) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(CommandError::WrongArity("dbsize").into());
+    }
+    let len = store.lock().keys().count();
+    Ok(Value::Integer(len as i64))
+}
+
+/// `EXPIRE` / `PEXPIRE` / `EXPIREAT` / `PEXPIREAT key time [NX | XX | GT | LT]`
+///
+/// Returns 1 if the expiry was set, or 0 if the key does not exist or a
+/// condition was not met. A time in the past deletes the key.
+pub fn handle_expire(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let time = parse_int(&args[1])?;
+    let mut conditions = Vec::new();
+    for arg in &args[2..] {
+        let condition =
+            Condition::parse(arg).ok_or_else(|| CommandError::UnsupportedOption(arg.clone()))?;
+        conditions.push(condition);
+    }
+    if conditions.contains(&Condition::Nx) && conditions.len() > 1 {
+        return Err(CommandError::NxAndOtherOptions.into());
+    }
+    if conditions.contains(&Condition::Gt) && conditions.contains(&Condition::Lt) {
+        return Err(CommandError::GtAndLt.into());
+    }
+
+    let now = SystemTime::now();
+    let (unit_ms, base_ms) = match command {
+        "expire" => (1000, unix_millis(now)),
+        "pexpire" => (1, unix_millis(now)),
+        "expireat" => (1000, 0),
+        _ => (1, 0),
+    };
+    let at = time
+        .checked_mul(unit_ms)
+        .and_then(|ms| ms.checked_add(base_ms))
+        .ok_or(CommandError::InvalidExpire(command))?;
+    // Times before the epoch have passed all the same.
+    let expiry = UNIX_EPOCH + Duration::from_millis(at.max(0) as u64);
+
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let data = match keyspace.get(key) {
+        Some(data) => data,
+        None => return Ok(Value::Integer(0)),
+    };
+    if !conditions
+        .iter()
+        .all(|condition| condition.allows(data.expiry, expiry))
+    {
+        return Ok(Value::Integer(0));
+    }
+    if expiry <= now {
+        keyspace.remove(key);
+    } else {
+        keyspace.set_expiry(key, Some(expiry));
+    }
+    Ok(Value::Integer(1))
+}
+
+/// `TTL` / `PTTL` / `EXPIRETIME` / `PEXPIRETIME key`
+///
+/// Returns the remaining time to live, or the Unix time the key expires at
+/// for the `EXPIRETIME` variants. -1 means the key has no expiry and -2 that
+/// it does not exist.
+pub fn handle_ttl(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let expiry = match store.lock().get(&args[0]) {
+        Some(data) => data.expiry,
+        None => return Ok(Value::Integer(-2)),
+    };
+    let expiry = match expiry {
+        Some(expiry) => expiry,
+        None => return Ok(Value::Integer(-1)),
+    };
+    let reply = match command {
+        "expiretime" => unix_millis(expiry) / 1000,
+        "pexpiretime" => unix_millis(expiry),
+        _ => {
+            let ms = expiry
+                .duration_since(SystemTime::now())
+                .map(|ttl| ttl.as_millis() as i64)
+                .unwrap_or(0);
+            if command == "pttl" {
+                ms
+            } else {
+                (ms + 500) / 1000
+            }
+        }
+    };
+    Ok(Value::Integer(reply))
+}
+
+/// `PERSIST key`
+///
+/// Returns 1 if the expiry was removed, or 0 if the key does not exist or
+/// has no expiry.
+pub fn handle_persist(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("persist").into());
+    }
+    let mut keyspace = store.lock();
+    let removed = keyspace.get(&args[0]).is_some() && keyspace.set_expiry(&args[0], None).is_some();
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `KEYS pattern`
+pub fn handle_keys(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("keys").into());
+    }
+    let keyspace = store.lock();
+    let keys = keyspace
+        .keys()
+        .filter(|key| glob::matches(&args[0], key))
+        .map(|key| Value::Bulk(key.clone()))
+        .collect();
+    Ok(Value::Array(keys))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+pub fn handle_scan(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() {
+        return Err(CommandError::WrongArity("scan").into());
+    }
+    let options = ScanOptions::parse(&args[0], &args[1..], "type")?;
+    let keyspace = store.lock();
+    let (next, page) = keyspace.scan(options.cursor, options.count);
+    let keys = page
+        .into_iter()
+        .filter(|(key, data)| {
+            options.matches(key)
+                && options
+                    .flag
+                    .is_none_or(|kind| kind.eq_ignore_ascii_case(data.value.type_name()))
+        })
+        .map(|(key, _)| Value::Bulk(key.clone()))
+        .collect();
+    Ok(scan_reply(next, keys))
+}
+
+/// The arguments of `SCAN` and its `HSCAN`, `SSCAN` and `ZSCAN` variants.
+pub struct ScanOptions<'a> {
+    pub cursor: u64,
+    pub pattern: Option<&'a str>,
+    pub count: usize,
+    /// The value of the `TYPE` option, or the option itself for `NOVALUES`,
+    /// if the command takes one and it was given.
+    pub flag: Option<&'a str>,
+}
+
+impl<'a> ScanOptions<'a> {
+    /// Parses the cursor and the options after it. `extra` names the option
+    /// only some of the commands accept: `type`, which takes a value,
+    /// `novalues`, which does not, or an empty string for none.
+    pub fn parse(cursor: &str, options: &'a [String], extra: &str) -> Result<Self, CommandError> {
+        let cursor = cursor
+            .parse::<u64>()
+            .map_err(|_| CommandError::InvalidCursor)?;
+        let mut parsed = ScanOptions {
+            cursor,
+            pattern: None,
+            count: DEFAULT_SCAN_COUNT,
+            flag: None,
+        };
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = option.to_lowercase();
+            match option.as_str() {
+                "match" => {
+                    parsed.pattern = Some(options.next().ok_or(CommandError::Syntax)?);
+                }
+                "count" => {
+                    let n = parse_int(options.next().ok_or(CommandError::Syntax)?)?;
+                    if n < 1 {
+                        return Err(CommandError::Syntax);
+                    }
+                    parsed.count = n as usize;
+                }
+                "type" if extra == "type" => {
+                    parsed.flag = Some(options.next().ok_or(CommandError::Syntax)?);
+                }
+                "novalues" if extra == "novalues" => parsed.flag = Some("novalues"),
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.pattern
+            .is_none_or(|pattern| glob::matches(pattern, name))
+    }
+}
+
+pub fn scan_reply(cursor: u64, items: Vec<Value>) -> Value {
+    Value::Array(vec![Value::Bulk(cursor.to_string()), Value::Array(items)])
+}
+
+/// `RANDOMKEY`
+pub fn handle_randomkey(args: Vec<Value>, store: &Store) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(CommandError::WrongArity("randomkey").into());
+    }
+    let keyspace = store.lock();
+    Ok(random_key(&keyspace)
+        .map(Value::Bulk)
+        .unwrap_or(Value::Null))
+}
+
+fn random_key(keyspace: &Keyspace) -> Option<String> {
+    let keys: Vec<&String> = keyspace.keys().collect();
+    if keys.is_empty() {
+        return None;
+    }
+    Some(keys[random_index(keys.len())].clone())
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,385 @@
+use anyhow::Result;
+use resp::Value;
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bulk_array,
+    db::{Keyspace, Store},
+    error::CommandError,
+    parse_int, unpack_args,
+};
+
+/// Which end of a list a command operates on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn push(self, list: &mut VecDeque<String>, element: String) {
+        match self {
+            End::Left => list.push_front(element),
+            End::Right => list.push_back(element),
+        }
+    }
+
+    fn pop(self, list: &mut VecDeque<String>) -> Option<String> {
+        match self {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            End::Left => "LEFT",
+            End::Right => "RIGHT",
+        }
+    }
+}
+
+impl FromStr for End {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "left" => Ok(End::Left),
+            "right" => Ok(End::Right),
+            _ => Err(CommandError::Syntax),
+        }
+    }
+}
+
+/// `LPUSH` / `RPUSH key element [element ...]`
+pub fn handle_push(args: Vec<Value>, store: &Store, end: End) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity(match end {
+            End::Left => "lpush",
+            End::Right => "rpush",
+        })
+        .into());
+    }
+    let mut keyspace = store.lock();
+    let list = keyspace.list_or_create(&args[0])?;
+    for element in &args[1..] {
+        end.push(list, element.clone());
+    }
+    Ok(Value::Integer(list.len() as i64))
+}
+
+/// `LPOP` / `RPOP key [count]`
+pub fn handle_pop(args: Vec<Value>, store: &Store, end: End) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() || args.len() > 2 {
+        return Err(CommandError::WrongArity(match end {
+            End::Left => "lpop",
+            End::Right => "rpop",
+        })
+        .into());
+    }
+    let count = match args.get(1) {
+        Some(count) => {
+            let count = parse_int(count)?;
+            if count < 0 {
+                return Err(CommandError::NotPositive.into());
+            }
+            Some(count as usize)
+        }
+        None => None,
+    };
+
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let list = match keyspace.list(key)? {
+        Some(list) => list,
+        None if count.is_some() => return Ok(Value::NullArray),
+        None => return Ok(Value::Null),
+    };
+    let mut popped = Vec::new();
+    for _ in 0..count.unwrap_or(1) {
+        match end.pop(list) {
+            Some(element) => popped.push(element),
+            None => break,
+        }
+    }
+    keyspace.remove_if_empty(key);
+
+    match count {
+        Some(_) => Ok(Value::Array(popped.into_iter().map(Value::Bulk).collect())),
+        None => Ok(popped.pop().map(Value::Bulk).unwrap_or(Value::Null)),
+    }
+}
+
+/// `LLEN key`
+pub fn handle_llen(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("llen").into());
+    }
+    let len = store.lock().list(&args[0])?.map_or(0, |list| list.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `LRANGE key start stop`
+pub fn handle_lrange(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("lrange").into());
+    }
+    let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+    let mut keyspace = store.lock();
+    let list = match keyspace.list(&args[0])? {
+        Some(list) => list,
+        None => return Ok(Value::Array(Vec::new())),
+    };
+    let elements = match range(start, stop, list.len()) {
+        Some((start, stop)) => list.range(start..=stop).cloned().map(Value::Bulk).collect(),
+        None => Vec::new(),
+    };
+    Ok(Value::Array(elements))
+}
+
+/// `LINDEX key index`
+pub fn handle_lindex(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("lindex").into());
+    }
+    let index = parse_int(&args[1])?;
+    let mut keyspace = store.lock();
+    let element = keyspace
+        .list(&args[0])?
+        .and_then(|list| index_of(index, list.len()).and_then(|i| list.get(i)))
+        .cloned();
+    Ok(element.map(Value::Bulk).unwrap_or(Value::Null))
+}
+
+/// `LSET key index element`
+pub fn handle_lset(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("lset").into());
+    }
+    let index = parse_int(&args[1])?;
+    let mut keyspace = store.lock();
+    let list = keyspace.list(&args[0])?.ok_or(CommandError::NoSuchKey)?;
+    let i = index_of(index, list.len()).ok_or(CommandError::IndexOutOfRange)?;
+    list[i] = args[2].clone();
+    Ok(Value::String("OK".to_string()))
+}
+
+/// `LREM key count element`
+pub fn handle_lrem(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("lrem").into());
+    }
+    let count = parse_int(&args[1])?;
+    let (key, element) = (&args[0], &args[2]);
+    let mut keyspace = store.lock();
+    let list = match keyspace.list(key)? {
+        Some(list) => list,
+        None => return Ok(Value::Integer(0)),
+    };
+
+    // A positive count removes from the head, a negative one from the tail
+    // and zero removes every occurrence.
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs() as usize
+    };
+    let mut positions: Vec<usize> = if count < 0 {
+        (0..list.len())
+            .rev()
+            .filter(|&i| list[i] == *element)
+            .collect()
+    } else {
+        (0..list.len()).filter(|&i| list[i] == *element).collect()
+    };
+    positions.truncate(limit);
+    positions.sort_unstable_by(|a, b| b.cmp(a));
+    for &i in &positions {
+        list.remove(i);
+    }
+    keyspace.remove_if_empty(key);
+    Ok(Value::Integer(positions.len() as i64))
+}
+
+/// `LTRIM key start stop`
+pub fn handle_ltrim(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("ltrim").into());
+    }
+    let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    if let Some(list) = keyspace.list(key)? {
+        match range(start, stop, list.len()) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+        keyspace.remove_if_empty(key);
+    }
+    Ok(Value::String("OK".to_string()))
+}
+
+/// `LMOVE source destination LEFT|RIGHT LEFT|RIGHT`
+pub fn handle_lmove(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 4 {
+        return Err(CommandError::WrongArity("lmove").into());
+    }
+    let (from, to) = (args[2].parse::<End>()?, args[3].parse::<End>()?);
+    let element = move_element(&mut store.lock(), &args[0], &args[1], from, to)?;
+    Ok(element.map(Value::Bulk).unwrap_or(Value::Null))
+}
+
+/// `BLPOP` / `BRPOP key [key ...] timeout` and
+/// `BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout`.
+///
+/// Blocks until an element can be popped or the timeout, in seconds, expires.
+/// Zero blocks forever. A popped element is logged as the non-blocking
+/// command with the same effect. The wait ends early once `connected`
+/// reports that the client is gone.
+pub fn handle_blocking(
+    command: &str,
+    args: Vec<Value>,
+    store: &Store,
+    connected: &dyn Fn() -> bool,
+) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    let arity_ok = match command {
+        "blmove" => args.len() == 5,
+        _ => args.len() >= 2,
+    };
+    if !arity_ok {
+        return Err(CommandError::WrongArity(match command {
+            "blpop" => "blpop",
+            "brpop" => "brpop",
+            _ => "blmove",
+        })
+        .into());
+    }
+    let (keys, timeout) = args.split_at(args.len() - 1);
+    let deadline = parse_timeout(&timeout[0])?
+        .map(|timeout| {
+            Instant::now()
+                .checked_add(timeout)
+                .ok_or(CommandError::TimeoutOutOfRange)
+        })
+        .transpose()?;
+
+    if command == "blmove" {
+        let (source, destination) = (&keys[0], &keys[1]);
+        let (from, to) = (keys[2].parse::<End>()?, keys[3].parse::<End>()?);
+        let element = store.block(&keys[..1], deadline, connected, |keyspace, _| {
+            let element = move_element(keyspace, source, destination, from, to)?;
+            Ok(element.map(|element| {
+                let write = bulk_array(vec![
+                    "LMOVE".to_string(),
+                    source.clone(),
+                    destination.clone(),
+                    from.name().to_string(),
+                    to.name().to_string(),
+                ]);
+                (element, vec![write])
+            }))
+        })?;
+        return Ok(element.map(Value::Bulk).unwrap_or(Value::Null));
+    }
+
+    let end = if command == "blpop" {
+        End::Left
+    } else {
+        End::Right
+    };
+    let pop = match end {
+        End::Left => "LPOP",
+        End::Right => "RPOP",
+    };
+    let popped = store.block(keys, deadline, connected, |keyspace, key| {
+        let element = keyspace.list(key)?.and_then(|list| end.pop(list));
+        keyspace.remove_if_empty(key);
+        Ok(element.map(|element| {
+            let write = bulk_array(vec![pop.to_string(), key.to_string()]);
+            ((key.to_string(), element), vec![write])
+        }))
+    })?;
+    Ok(match popped {
+        Some((key, element)) => Value::Array(vec![Value::Bulk(key), Value::Bulk(element)]),
+        None => Value::NullArray,
+    })
+}
+
+/// Parses a blocking timeout in seconds. Zero means no timeout.
+fn parse_timeout(s: &str) -> Result<Option<Duration>, CommandError> {
+    let seconds = s
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or(CommandError::InvalidTimeout)?;
+    if seconds < 0.0 {
+        return Err(CommandError::NegativeTimeout);
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| CommandError::TimeoutOutOfRange)
+}
+
+/// Pops an element from one end of `source` and pushes it onto `destination`,
+/// which may be the same list.
+fn move_element(
+    keyspace: &mut Keyspace,
+    source: &str,
+    destination: &str,
+    from: End,
+    to: End,
+) -> Result<Option<String>, CommandError> {
+    // Fail before touching the source if the destination has the wrong type.
+    keyspace.list(destination)?;
+    let element = match keyspace.list(source)?.and_then(|list| from.pop(list)) {
+        Some(element) => element,
+        None => return Ok(None),
+    };
+    keyspace.remove_if_empty(source);
+    to.push(keyspace.list_or_create(destination)?, element.clone());
+    Ok(Some(element))
+}
+
+/// Resolves a possibly negative index into a position within a list of `len` elements.
+fn index_of(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+/// Resolves an inclusive `start..=stop` range with Redis semantics: negative
+/// indices count from the end and out of range bounds are clamped. Returns
+/// `None` for an empty range.
+pub fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
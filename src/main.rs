@@ -1,44 +1,113 @@
 use anyhow::Result;
-use clap::Parser;
 use resp::{Decoder, Value};
 use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
     io::{BufReader, ErrorKind, Write},
     net::{TcpListener, TcpStream},
+    path::Path,
     thread,
-    time::{Duration, SystemTime},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+mod aof;
+mod config;
 mod db;
+mod error;
+mod glob;
+mod hash;
+mod keys;
+mod list;
+mod rdb;
+mod replication;
+mod set;
+mod stream;
+mod string;
+mod zset;
+use aof::Aof;
+use config::Config;
 use db::Store;
+use error::CommandError;
+use list::End;
+use rdb::Saver;
+use replication::Replication;
 
-static PORT: u16 = 6379;
 static REP_ID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
 
-#[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[arg(short, long, default_value_t = PORT)]
-    port: u16,
-    #[arg(short, long, num_args = 1)]
-    replicaof: Option<Vec<String>>,
-}
-
 fn main() {
-    let cmd_args = Args::parse();
+    let cmd_args = match config::load_args() {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    };
     let port = cmd_args.port;
     let listener = TcpListener::bind(format!("127.0.0.1:{port}")).expect("Failed to bind to port");
 
     println!("Server listening on port {}", port);
 
-    let store = Store::new();
+    if let Some(save) = &cmd_args.save {
+        if let Err(e) = rdb::parse_save_policies(save) {
+            println!("{e}");
+        }
+    }
+    let config = Config::new(cmd_args.clone());
+
+    let store = Store::new(cmd_args.databases);
+    let rdb_path = config.rdb_path();
+    let aof_path = Path::new(&cmd_args.dir).join(&cmd_args.appendfilename);
+    let aof_enabled = cmd_args.appendonly == "yes";
 
-    if let Some(replica_addrs) = &cmd_args.replicaof {
-        for addr in replica_addrs {
-            println!("Connecting to replica at {}", addr);
-            match TcpStream::connect(addr) {
-                Ok(_) => println!("Connected to replica at {}", addr),
-                Err(e) => println!("Failed to connect to replica at {}: {}", addr, e),
+    // The append only file is more complete than the last snapshot, so it is
+    // preferred when it exists.
+    let aof_loaded = aof_enabled
+        && match aof::load(&aof_path, &store) {
+            Ok(Some(count)) => {
+                println!("Replayed {} commands from {}", count, aof_path.display());
+                true
             }
+            Ok(None) => false,
+            Err(e) => {
+                println!("Failed to load {}: {}", aof_path.display(), e);
+                true
+            }
+        };
+    if !aof_loaded {
+        match rdb::load(&rdb_path, &store) {
+            Ok(count) => println!("Loaded {} keys from {}", count, rdb_path.display()),
+            Err(e) => println!("Failed to load {}: {}", rdb_path.display(), e),
+        }
+    }
+    let aof =
+        Aof::new(aof_path, config.clone(), aof_enabled).expect("Failed to open append only file");
+    if aof_enabled && !aof_loaded {
+        // Seed the new log with the dataset loaded from the snapshot.
+        aof.rewrite(&store);
+    }
+    store.start_active_expire(config.clone());
+    let saver = Saver::new(config.clone(), store.clone());
+    saver.start_policies();
+    let replication = Replication::new(config.clone());
+    store.set_write_log({
+        let replication = replication.clone();
+        let aof = aof.clone();
+        Box::new(move |db, command| {
+            replication.propagate(db, command);
+            aof.append(db, command);
+        })
+    });
+
+    if let Some(master_addrs) = &cmd_args.replicaof {
+        for addr in master_addrs {
+            let master = replication::parse_master_addr(addr);
+            replication::start_replica(
+                master,
+                port,
+                store.clone(),
+                replication.clone(),
+                aof.clone(),
+            );
         }
     }
 
@@ -47,9 +116,19 @@ fn main() {
             Ok(stream) => {
                 println!("Accepted new connection");
                 let store_clone = store.clone();
-                let cmd_args_clone = cmd_args.clone();
+                let config_clone = config.clone();
+                let replication_clone = replication.clone();
+                let saver_clone = saver.clone();
+                let aof_clone = aof.clone();
                 thread::spawn(move || {
-                    handle_client(stream, store_clone, cmd_args_clone);
+                    handle_client(
+                        stream,
+                        store_clone,
+                        config_clone,
+                        replication_clone,
+                        saver_clone,
+                        aof_clone,
+                    );
                 });
             }
             Err(e) => {
@@ -59,21 +138,92 @@ fn main() {
     }
 }
 
-fn handle_client(mut stream: TcpStream, store: Store, cmd_args: Args) {
+fn handle_client(
+    mut stream: TcpStream,
+    mut store: Store,
+    config: Config,
+    replication: Replication,
+    saver: Saver,
+    aof: Aof,
+) {
+    // Set once this connection turns out to be a replica that sent PSYNC.
+    let mut replica_id = None;
+    // A single decoder for the whole connection, so pipelined commands that
+    // were buffered together are not lost between iterations.
+    let mut decoder = Decoder::new(BufReader::new(stream.try_clone().unwrap()));
     loop {
-        let bufreader = BufReader::new(&stream);
-        let mut decoder = Decoder::new(bufreader);
-
         let result = match decoder.decode() {
             Ok(value) => {
                 let (command, args) = extract_command(&value).unwrap();
-                match command.to_lowercase().as_str() {
-                    "ping" => Ok(Value::String("PONG".to_string())),
-                    "echo" => Ok(args.first().unwrap().clone()),
-                    "set" => handle_set(args, &store),
-                    "get" => handle_get(args, &store),
-                    "info" => handle_info(&cmd_args),
-                    c => Err(anyhow::anyhow!("Unknown command: {c}")),
+                let command = command.to_lowercase();
+                match command.as_str() {
+                    "info" => handle_info(&config, &store, &replication, &saver, &aof),
+                    "config" => handle_config(args, &config),
+                    "bgrewriteaof" => handle_bgrewriteaof(&aof, &store),
+                    "save" => handle_save(&saver),
+                    "bgsave" => handle_bgsave(&saver),
+                    "lastsave" => Ok(Value::Integer(saver.last_save() as i64)),
+                    "wait" => replication::handle_wait(args, &replication),
+                    "select" => keys::handle_select(args, &mut store),
+                    "xread" => stream::handle_xread(args, &store, &|| is_connected(&stream)),
+                    // Replicas and the append only file get commands with the
+                    // same effect that neither block nor depend on chance or
+                    // the clock. Blocking commands order and log them while
+                    // they wait, as the wait has to release the write order.
+                    "blpop" | "brpop" | "blmove" | "xreadgroup" => {
+                        let connected = || is_connected(&stream);
+                        let result = match command.as_str() {
+                            "xreadgroup" => stream::handle_xreadgroup(args, &store, &connected),
+                            c => list::handle_blocking(c, args, &store, &connected),
+                        };
+                        store.wake_blocked();
+                        result
+                    }
+                    "spop" | "xadd" | "xclaim" | "xautoclaim" => {
+                        let order = store.order_writes();
+                        let result = match command.as_str() {
+                            "spop" => set::handle_spop(args, &store),
+                            "xadd" => stream::handle_xadd(args, &store),
+                            "xclaim" => stream::handle_xclaim(args, &store),
+                            _ => stream::handle_xautoclaim(args, &store),
+                        };
+                        result.map(|(reply, writes)| {
+                            if !writes.is_empty() {
+                                store.mark_dirty();
+                                store.log_writes(&writes);
+                                drop(order);
+                                store.wake_blocked();
+                            }
+                            reply
+                        })
+                    }
+                    "replconf" => {
+                        match replication::handle_replconf(args, replica_id, &replication) {
+                            Ok(Some(value)) => Ok(value),
+                            Ok(None) => continue,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    "psync" => {
+                        match replication::handle_psync(args, &mut stream, &store, &replication) {
+                            Ok(id) => replica_id = Some(id),
+                            Err(e) => println!("error: {e}"),
+                        }
+                        continue;
+                    }
+                    c if is_write_command(c) => {
+                        let order = store.order_writes();
+                        let result = execute_command(c, args, &store);
+                        if matches!(result, Ok(ref v) if !v.is_error()) {
+                            store.log_writes(std::slice::from_ref(&value));
+                            drop(order);
+                            // Only now, so that an element popped by a
+                            // blocked client is propagated after its push.
+                            store.wake_blocked();
+                        }
+                        result
+                    }
+                    c => execute_command(c, args, &store),
                 }
             }
             Err(e) => {
@@ -85,21 +235,227 @@ fn handle_client(mut stream: TcpStream, store: Store, cmd_args: Args) {
             }
         };
 
-        match result {
-            Ok(value) => {
-                stream.write_all(&value.encode()).unwrap();
-            }
-            Err(e) => {
-                println!("error: {e}");
-            }
+        let reply = match result {
+            Ok(value) => value,
+            Err(e) => match e.downcast_ref::<CommandError>() {
+                Some(e) => Value::Error(e.to_string()),
+                None => {
+                    println!("error: {e}");
+                    continue;
+                }
+            },
+        };
+        if let Err(e) = stream.write_all(&reply.encode()) {
+            println!("client disconnected: {e}");
+            return;
         }
     }
 }
 
+/// Whether the client on `stream` is still there, for commands that block
+/// until something happens. This peeks without blocking, so commands the
+/// client pipelined after the blocking one stay unread.
+fn is_connected(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let connected = match stream.peek(&mut [0]) {
+        Ok(n) => n > 0,
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).is_ok() && connected
+}
+
+/// Runs a command that only depends on the store. This is shared by client
+/// connections, the replication link, which applies the master's writes, and
+/// the append only file replay.
+fn execute_command(command: &str, args: Vec<Value>, store: &Store) -> Result<Value> {
+    let result = match command {
+        "ping" => Ok(Value::String("PONG".to_string())),
+        "echo" => Ok(args.first().unwrap().clone()),
+        "set" => string::handle_set(args, store),
+        "get" => string::handle_get(args, store),
+        "getdel" => string::handle_getdel(args, store),
+        "getex" => string::handle_getex(args, store),
+        "mget" => string::handle_mget(args, store),
+        "mset" => string::handle_mset(args, store, "mset"),
+        "msetnx" => string::handle_mset(args, store, "msetnx"),
+        "append" => string::handle_append(args, store),
+        "strlen" => string::handle_strlen(args, store),
+        "getrange" => string::handle_getrange(args, store),
+        "setrange" => string::handle_setrange(args, store),
+        "del" => keys::handle_del(args, store, "del"),
+        "unlink" => keys::handle_del(args, store, "unlink"),
+        "exists" => keys::handle_exists(args, store, "exists"),
+        "touch" => keys::handle_exists(args, store, "touch"),
+        "type" => keys::handle_type(args, store),
+        "rename" => keys::handle_rename(args, store, "rename"),
+        "renamenx" => keys::handle_rename(args, store, "renamenx"),
+        "copy" => keys::handle_copy(args, store),
+        "randomkey" => keys::handle_randomkey(args, store),
+        "swapdb" => keys::handle_swapdb(args, store),
+        "move" => keys::handle_move(args, store),
+        "flushdb" => keys::handle_flush(args, store, "flushdb"),
+        "flushall" => keys::handle_flush(args, store, "flushall"),
+        "dbsize" => keys::handle_dbsize(args, store),
+        "keys" => keys::handle_keys(args, store),
+        "scan" => keys::handle_scan(args, store),
+        "expire" => keys::handle_expire(args, store, "expire"),
+        "pexpire" => keys::handle_expire(args, store, "pexpire"),
+        "expireat" => keys::handle_expire(args, store, "expireat"),
+        "pexpireat" => keys::handle_expire(args, store, "pexpireat"),
+        "ttl" => keys::handle_ttl(args, store, "ttl"),
+        "pttl" => keys::handle_ttl(args, store, "pttl"),
+        "expiretime" => keys::handle_ttl(args, store, "expiretime"),
+        "pexpiretime" => keys::handle_ttl(args, store, "pexpiretime"),
+        "persist" => keys::handle_persist(args, store),
+        "incr" => string::handle_incr(args, store, "incr"),
+        "decr" => string::handle_incr(args, store, "decr"),
+        "incrby" => string::handle_incr(args, store, "incrby"),
+        "decrby" => string::handle_incr(args, store, "decrby"),
+        "incrbyfloat" => string::handle_incrbyfloat(args, store),
+        "lpush" => list::handle_push(args, store, End::Left),
+        "rpush" => list::handle_push(args, store, End::Right),
+        "lpop" => list::handle_pop(args, store, End::Left),
+        "rpop" => list::handle_pop(args, store, End::Right),
+        "llen" => list::handle_llen(args, store),
+        "lrange" => list::handle_lrange(args, store),
+        "lindex" => list::handle_lindex(args, store),
+        "lset" => list::handle_lset(args, store),
+        "lrem" => list::handle_lrem(args, store),
+        "ltrim" => list::handle_ltrim(args, store),
+        "lmove" => list::handle_lmove(args, store),
+        "hset" => hash::handle_hset(args, store),
+        "hget" => hash::handle_hget(args, store),
+        "hmget" => hash::handle_hmget(args, store),
+        "hdel" => hash::handle_hdel(args, store),
+        "hgetall" => hash::handle_hgetall(args, store),
+        "hlen" => hash::handle_hlen(args, store),
+        "hexists" => hash::handle_hexists(args, store),
+        "hincrby" => hash::handle_hincrby(args, store),
+        "hscan" => hash::handle_hscan(args, store),
+        "hexpire" => hash::handle_hexpire(args, store, "hexpire"),
+        "hpexpire" => hash::handle_hexpire(args, store, "hpexpire"),
+        "hexpireat" => hash::handle_hexpire(args, store, "hexpireat"),
+        "hpexpireat" => hash::handle_hexpire(args, store, "hpexpireat"),
+        "httl" => hash::handle_httl(args, store, "httl"),
+        "hpttl" => hash::handle_httl(args, store, "hpttl"),
+        "hpersist" => hash::handle_hpersist(args, store),
+        "sadd" => set::handle_sadd(args, store),
+        "srem" => set::handle_srem(args, store),
+        "smembers" => set::handle_smembers(args, store),
+        "sscan" => set::handle_sscan(args, store),
+        "sismember" => set::handle_sismember(args, store),
+        "scard" => set::handle_scard(args, store),
+        "sinter" => set::handle_combine(args, store, "sinter"),
+        "sunion" => set::handle_combine(args, store, "sunion"),
+        "sdiff" => set::handle_combine(args, store, "sdiff"),
+        "sinterstore" => set::handle_combine_store(args, store, "sinterstore"),
+        "sunionstore" => set::handle_combine_store(args, store, "sunionstore"),
+        "sdiffstore" => set::handle_combine_store(args, store, "sdiffstore"),
+        "srandmember" => set::handle_srandmember(args, store),
+        "zadd" => zset::handle_zadd(args, store),
+        "zincrby" => zset::handle_zincrby(args, store),
+        "zrem" => zset::handle_zrem(args, store),
+        "zscore" => zset::handle_zscore(args, store),
+        "zscan" => zset::handle_zscan(args, store),
+        "zcard" => zset::handle_zcard(args, store),
+        "zrank" => zset::handle_zrank(args, store, "zrank"),
+        "zrevrank" => zset::handle_zrank(args, store, "zrevrank"),
+        "zrange" => zset::handle_zrange(args, store, "zrange"),
+        "zrangebyscore" => zset::handle_zrange(args, store, "zrangebyscore"),
+        "zrevrangebyscore" => zset::handle_zrange(args, store, "zrevrangebyscore"),
+        "zrevrange" => zset::handle_zrange(args, store, "zrevrange"),
+        "zunionstore" => zset::handle_zstore(args, store, "zunionstore"),
+        "zinterstore" => zset::handle_zstore(args, store, "zinterstore"),
+        // Written entries always carry an explicit ID by now.
+        "xadd" => stream::handle_xadd(args, store).map(|(reply, _)| reply),
+        "xlen" => stream::handle_xlen(args, store),
+        "xrange" => stream::handle_xrange(args, store, "xrange"),
+        "xrevrange" => stream::handle_xrange(args, store, "xrevrange"),
+        "xtrim" => stream::handle_xtrim(args, store),
+        "xgroup" => stream::handle_xgroup(args, store),
+        "xack" => stream::handle_xack(args, store),
+        "xpending" => stream::handle_xpending(args, store),
+        // Propagated claims carry an explicit delivery time.
+        "xclaim" => stream::handle_xclaim(args, store).map(|(reply, _)| reply),
+        c => Err(anyhow::anyhow!("Unknown command: {c}")),
+    };
+    if is_write_command(command) && matches!(result, Ok(ref v) if !v.is_error()) {
+        store.mark_dirty();
+    }
+    result
+}
+
+/// Commands that modify the store and therefore have to be propagated to replicas.
+fn is_write_command(command: &str) -> bool {
+    matches!(
+        command,
+        "set"
+            | "getdel"
+            | "getex"
+            | "mset"
+            | "msetnx"
+            | "append"
+            | "setrange"
+            | "del"
+            | "unlink"
+            | "rename"
+            | "renamenx"
+            | "copy"
+            | "move"
+            | "swapdb"
+            | "flushdb"
+            | "flushall"
+            | "expire"
+            | "pexpire"
+            | "expireat"
+            | "pexpireat"
+            | "persist"
+            | "incr"
+            | "decr"
+            | "incrby"
+            | "decrby"
+            | "incrbyfloat"
+            | "lpush"
+            | "rpush"
+            | "lpop"
+            | "rpop"
+            | "lset"
+            | "lrem"
+            | "ltrim"
+            | "lmove"
+            | "hset"
+            | "hdel"
+            | "hincrby"
+            | "hexpire"
+            | "hpexpire"
+            | "hexpireat"
+            | "hpexpireat"
+            | "hpersist"
+            | "sadd"
+            | "srem"
+            | "sinterstore"
+            | "sunionstore"
+            | "sdiffstore"
+            | "zadd"
+            | "zincrby"
+            | "zrem"
+            | "zunionstore"
+            | "zinterstore"
+            | "xadd"
+            | "xtrim"
+            | "xgroup"
+            | "xack"
+            | "xclaim"
+    )
+}
+
 fn extract_command(value: &Value) -> Result<(String, Vec<Value>)> {
     match value {
         Value::Array(a) => {
-            let command = unpack_bulk_string(a.first().unwrap())?;
+            let command = a.first().ok_or_else(|| anyhow::anyhow!("Empty command"))?;
+            let command = unpack_bulk_string(command)?;
             let args = a.iter().skip(1).cloned().collect();
             Ok((command, args))
         }
@@ -114,66 +470,168 @@ fn unpack_bulk_string(value: &Value) -> Result<String> {
     }
 }
 
-fn handle_set(args: Vec<Value>, store: &Store) -> Result<Value> {
-    if args.len() < 2 {
-        return Ok(Value::Error(
-            "wrong number of arguments for 'set' command".to_string(),
-        ));
-    }
-    let mut iter = args.into_iter();
-    let key = unpack_bulk_string(&iter.next().unwrap()).unwrap();
-    let value = unpack_bulk_string(&iter.next().unwrap()).unwrap();
-    let mut expiry: Option<SystemTime> = None;
+fn bulk_array(parts: Vec<String>) -> Value {
+    Value::Array(parts.into_iter().map(Value::Bulk).collect())
+}
 
-    if let Some(v) = iter.next() {
-        match v {
-            Value::Bulk(option) => match option.to_lowercase().as_str() {
-                "px" => {
-                    let ms = match iter.next() {
-                        Some(Value::Bulk(arg)) => arg.parse::<u64>().unwrap(),
-                        _ => {
-                            return Ok(Value::Error("argument is not a bulk string".to_string()));
-                        }
-                    };
-                    expiry = Some(SystemTime::now() + Duration::from_millis(ms));
-                }
-                _ => {
-                    return Ok(Value::Error("option not supported".to_string()));
-                }
-            },
-            _ => {
-                return Ok(Value::Error("option is not a bulk string".to_string()));
+fn unpack_args(args: &[Value]) -> Result<Vec<String>> {
+    args.iter().map(unpack_bulk_string).collect()
+}
+
+/// Returns a random index below `len`. There is no random number generator
+/// among the dependencies, so this relies on the randomly seeded std hasher.
+fn random_index(len: usize) -> usize {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    (hasher.finish() % len as u64) as usize
+}
+
+fn parse_int(s: &str) -> Result<i64, CommandError> {
+    s.parse::<i64>().map_err(|_| CommandError::NotInteger)
+}
+
+/// Parses a float the way Redis does, accepting `inf` but not `nan`.
+fn parse_float(s: &str) -> Result<f64, CommandError> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|f| !f.is_nan())
+        .ok_or(CommandError::NotFloat)
+}
+
+/// Formats a float the way Redis replies with it, switching to exponent
+/// notation for very large or small magnitudes.
+fn format_float(f: f64) -> String {
+    let magnitude = f.abs();
+    if f.is_finite() && magnitude != 0.0 && !(1e-5..1e17).contains(&magnitude) {
+        let formatted = format!("{f:e}");
+        match formatted.split_once('e') {
+            Some((mantissa, exponent)) if !exponent.starts_with('-') => {
+                format!("{mantissa}e+{exponent}")
             }
+            _ => formatted,
         }
+    } else {
+        f.to_string()
     }
+}
 
-    store.write(key, value, expiry).unwrap();
-    Ok(Value::String("OK".to_string()))
+fn handle_save(saver: &Saver) -> Result<Value> {
+    match saver.save() {
+        Ok(()) => Ok(Value::String("OK".to_string())),
+        Err(e) => Ok(Value::Error(format!("save failed: {e}"))),
+    }
 }
 
-fn handle_get(args: Vec<Value>, store: &Store) -> Result<Value> {
-    if args.len() < 1 {
-        return Ok(Value::Error(
-            "wrong number of arguments for 'get' command".to_string(),
-        ));
+fn handle_bgsave(saver: &Saver) -> Result<Value> {
+    if saver.bgsave() {
+        Ok(Value::String("Background saving started".to_string()))
+    } else {
+        Ok(Value::Error(
+            "Background save already in progress".to_string(),
+        ))
     }
-    let key = unpack_bulk_string(&args[0]).unwrap();
-    match store.read(&key) {
-        Ok(value) => Ok(Value::Bulk(value)),
-        Err(e) => {
-            println!("error: {e}");
-            Ok(Value::Null)
+}
+
+fn handle_config(args: Vec<Value>, config: &Config) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    let subcommand = match args.first() {
+        Some(subcommand) => subcommand.to_lowercase(),
+        None => {
+            return Ok(Value::Error(
+                "wrong number of arguments for 'config' command".to_string(),
+            ))
         }
+    };
+    match subcommand.as_str() {
+        "get" if args.len() > 1 => {
+            let params = config.get(&args[1..]);
+            Ok(Value::Array(
+                params
+                    .into_iter()
+                    .flat_map(|(name, value)| [Value::Bulk(name), Value::Bulk(value)])
+                    .collect(),
+            ))
+        }
+        "set" if args.len() > 1 && args.len() % 2 == 1 => match config.set(&args[1..]) {
+            Ok(()) => Ok(Value::String("OK".to_string())),
+            Err(e) => Ok(Value::Error(e.to_string())),
+        },
+        "rewrite" => match config.rewrite() {
+            Ok(()) => Ok(Value::String("OK".to_string())),
+            Err(e) => Ok(Value::Error(e.to_string())),
+        },
+        "get" | "set" => Ok(Value::Error(format!(
+            "wrong number of arguments for 'config|{subcommand}' command"
+        ))),
+        _ => Ok(Value::Error(format!(
+            "unknown subcommand '{subcommand}'. Try CONFIG GET, CONFIG SET or CONFIG REWRITE."
+        ))),
     }
 }
 
-fn handle_info(cmd_args: &Args) -> Result<Value> {
-    let role = match cmd_args.replicaof {
-        Some(_) => "slave",
-        None => "master",
+fn handle_bgrewriteaof(aof: &Aof, store: &Store) -> Result<Value> {
+    if aof.rewrite(store) {
+        Ok(Value::String(
+            "Background append only file rewriting started".to_string(),
+        ))
+    } else {
+        Ok(Value::Error(
+            "Background append only file rewriting already in progress".to_string(),
+        ))
+    }
+}
+
+fn handle_info(
+    config: &Config,
+    store: &Store,
+    replication: &Replication,
+    saver: &Saver,
+    aof: &Aof,
+) -> Result<Value> {
+    let (role, offset) = match config.read().replicaof {
+        Some(_) => ("slave", replication.processed_offset()),
+        None => ("master", replication.offset()),
     };
-    let mut value = format!("role:{role}");
-    value.push_str(format!("master_replid:{REP_ID}").as_str());
-    value.push_str("master_repl_offset:0");
-    Ok(Value::Bulk(value))
+    let (backlog_size, backlog_start, backlog_len) = replication.backlog_info();
+    let (changes, bgsave_in_progress, last_bgsave_ok) = saver.status();
+    let mut lines = vec![
+        "# Persistence".to_string(),
+        format!("rdb_changes_since_last_save:{changes}"),
+        format!("rdb_bgsave_in_progress:{}", bgsave_in_progress as u8),
+        format!("rdb_last_save_time:{}", saver.last_save()),
+        format!(
+            "rdb_last_bgsave_status:{}",
+            if last_bgsave_ok { "ok" } else { "err" }
+        ),
+        format!("aof_enabled:{}", aof.enabled() as u8),
+        format!(
+            "aof_rewrite_in_progress:{}",
+            aof.rewrite_in_progress() as u8
+        ),
+        String::new(),
+        "# Stats".to_string(),
+        format!("expired_keys:{}", store.expired_keys()),
+        String::new(),
+        "# Replication".to_string(),
+        format!("role:{role}"),
+        format!("connected_slaves:{}", replication.replica_count()),
+        format!("master_replid:{REP_ID}"),
+        format!("master_repl_offset:{offset}"),
+        format!("repl_backlog_size:{backlog_size}"),
+        format!("repl_backlog_first_byte_offset:{backlog_start}"),
+        format!("repl_backlog_histlen:{backlog_len}"),
+        String::new(),
+        "# Keyspace".to_string(),
+    ];
+    for (db, (keys, expires)) in store.key_counts().into_iter().enumerate() {
+        if keys > 0 {
+            lines.push(format!("db{db}:keys={keys},expires={expires},avg_ttl=0"));
+        }
+    }
+    Ok(Value::Bulk(lines.join("\r\n")))
 }
@@ -0,0 +1,1398 @@
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::Config,
+    db::{
+        ConsumerGroup, Data, Hash, HashField, PendingEntry, RedisValue, ScanSet, SortedSet, Store,
+        Stream, StreamId,
+    },
+};
+
+/// RDB version 12, the first with hashes whose fields expire, which Redis 7.4
+/// introduced. Older versions would reject those.
+const MAGIC: &[u8] = b"REDIS0012";
+
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+/// A hash with field expiries, as written by Redis 7.4.
+const TYPE_HASH_METADATA: u8 = 24;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const STREAM_ITEM_DELETED: i64 = 1;
+const STREAM_ITEM_SAMEFIELDS: i64 = 2;
+/// How many entries go into one listpack of a stream, like Redis'
+/// `stream-node-max-entries` default.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// How often the save policies are checked.
+const SAVE_POLICY_INTERVAL: Duration = Duration::from_secs(1);
+
+struct SaveState {
+    last_save: SystemTime,
+    /// Value of `Store::dirty` when the last successful save was taken.
+    dirty_at_last_save: u64,
+    bgsave_in_progress: bool,
+    last_bgsave_ok: bool,
+}
+
+/// Writes snapshots of the store to the configured RDB file and keeps track
+/// of when that last happened.
+#[derive(Clone)]
+pub struct Saver {
+    config: Config,
+    store: Store,
+    state: Arc<Mutex<SaveState>>,
+}
+
+impl Saver {
+    pub fn new(config: Config, store: Store) -> Self {
+        let dirty = store.dirty();
+        Saver {
+            config,
+            store,
+            state: Arc::new(Mutex::new(SaveState {
+                last_save: SystemTime::now(),
+                dirty_at_last_save: dirty,
+                bgsave_in_progress: false,
+                last_bgsave_ok: true,
+            })),
+        }
+    }
+
+    /// Saves the store in the calling thread.
+    pub fn save(&self) -> Result<()> {
+        let dirty = self.store.dirty();
+        write_file(&self.config.rdb_path(), &encode(&self.store))?;
+        self.saved(dirty);
+        Ok(())
+    }
+
+    /// Saves the store on a background thread. Returns false if a background
+    /// save is already running.
+    pub fn bgsave(&self) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.bgsave_in_progress {
+                return false;
+            }
+            state.bgsave_in_progress = true;
+        }
+        let dirty = self.store.dirty();
+        let databases = self.store.snapshot();
+        let path = self.config.rdb_path();
+        let saver = self.clone();
+        thread::spawn(move || {
+            let result = write_file(&path, &encode_databases(databases));
+            if let Err(e) = &result {
+                println!("background save to {} failed: {}", path.display(), e);
+            } else {
+                saver.saved(dirty);
+            }
+            let mut state = saver.state.lock().unwrap();
+            state.bgsave_in_progress = false;
+            state.last_bgsave_ok = result.is_ok();
+        });
+        true
+    }
+
+    /// Unix time in seconds of the last successful save.
+    pub fn last_save(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .last_save
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Returns the number of changes since the last save, whether a
+    /// background save is running and whether the last one succeeded.
+    pub fn status(&self) -> (u64, bool, bool) {
+        let state = self.state.lock().unwrap();
+        (
+            self.store.dirty() - state.dirty_at_last_save,
+            state.bgsave_in_progress,
+            state.last_bgsave_ok,
+        )
+    }
+
+    /// Starts a thread that runs a background save whenever one of the
+    /// configured `save <seconds> <changes>` policies is met.
+    pub fn start_policies(&self) {
+        let saver = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_POLICY_INTERVAL);
+            let policies = match &saver.config.read().save {
+                Some(save) => parse_save_policies(save).unwrap_or_default(),
+                None => Vec::new(),
+            };
+            let (changes, in_progress, _) = saver.status();
+            let elapsed = {
+                let state = saver.state.lock().unwrap();
+                state.last_save.elapsed().unwrap_or_default().as_secs()
+            };
+            let due = policies
+                .iter()
+                .any(|&(seconds, min_changes)| changes >= min_changes && elapsed >= seconds);
+            if due && !in_progress {
+                println!("{changes} changes in {elapsed} seconds, saving");
+                saver.bgsave();
+            }
+        });
+    }
+
+    fn saved(&self, dirty: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.last_save = SystemTime::now();
+        state.dirty_at_last_save = state.dirty_at_last_save.max(dirty);
+    }
+}
+
+/// Parses `save` policies such as `"3600 1 300 100"` into `(seconds, changes)` pairs.
+pub fn parse_save_policies(specs: &[String]) -> Result<Vec<(u64, u64)>> {
+    let numbers = specs
+        .iter()
+        .flat_map(|spec| spec.split_whitespace())
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| anyhow::anyhow!("invalid save policy: {}", specs.join(" ")))?;
+    if numbers.len() % 2 != 0 {
+        return Err(anyhow::anyhow!("invalid save policy: {}", specs.join(" ")));
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Writes to a temporary file first so that a crash never leaves a partial RDB behind.
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads the RDB file at `path` into the store. A missing file is not an
+/// error and leaves the store empty. Returns the number of keys loaded.
+pub fn load(path: &Path, store: &Store) -> Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    restore(store, decode(&data, store.databases())?)
+}
+
+/// Inserts the keys of each database decoded from an RDB file into the
+/// store. Returns the number of keys inserted.
+pub fn restore(store: &Store, databases: Vec<Vec<(String, RedisValue)>>) -> Result<usize> {
+    let mut store = store.clone();
+    let mut count = 0;
+    for (db, entries) in databases.into_iter().enumerate() {
+        store.select(db);
+        count += entries.len();
+        for (key, data) in entries {
+            store.insert(key, data);
+        }
+    }
+    Ok(count)
+}
+
+/// Parses an RDB file into the keys of each database, indexed by database
+/// number, which has to be below `databases`. Keys whose expiry already
+/// passed are skipped.
+pub fn decode(data: &[u8], databases: usize) -> Result<Vec<Vec<(String, RedisValue)>>> {
+    if data.len() >= 8 {
+        let (body, checksum) = data.split_at(data.len() - 8);
+        let checksum = u64::from_le_bytes(checksum.try_into()?);
+        if checksum != 0 && checksum != crc64(body) {
+            return Err(anyhow::anyhow!("RDB checksum mismatch"));
+        }
+    }
+
+    let mut reader = Reader { data, pos: 0 };
+    let magic = reader.read_bytes(5)?;
+    if magic != b"REDIS" {
+        return Err(anyhow::anyhow!("not an RDB file"));
+    }
+    reader.read_bytes(4)?;
+
+    let now = SystemTime::now();
+    let limit = databases;
+    let mut databases: Vec<Vec<(String, RedisValue)>> = Vec::new();
+    let mut db = 0;
+    let mut expiry = None;
+    loop {
+        match reader.read_u8()? {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                reader.read_string()?;
+                reader.read_string()?;
+            }
+            OPCODE_MODULE_AUX => {
+                return Err(anyhow::anyhow!(
+                    "RDB files with module data are not supported"
+                ));
+            }
+            OPCODE_SELECTDB => {
+                let index = reader.read_length()?;
+                if index >= limit as u64 {
+                    return Err(anyhow::anyhow!(
+                        "the RDB file selects database {index}, but only {limit} databases are configured"
+                    ));
+                }
+                db = index as usize;
+            }
+            OPCODE_RESIZEDB => {
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                let ms = u64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_millis(ms));
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?);
+                expiry = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+            }
+            value_type => {
+                let key = reader.read_utf8()?;
+                let value = reader.read_value(value_type)?;
+                // A hash whose fields have all expired is left empty, and
+                // empty hashes are never kept around.
+                let empty = matches!(&value, Data::Hash(hash) if hash.is_empty());
+                if !empty && !matches!(expiry, Some(expiry) if expiry < now) {
+                    if databases.len() <= db {
+                        databases.resize_with(db + 1, Vec::new);
+                    }
+                    databases[db].push((key, RedisValue { value, expiry }));
+                }
+                expiry = None;
+            }
+        }
+    }
+    Ok(databases)
+}
+
+enum Length {
+    Len(u64),
+    /// A special encoding for strings stored as integers or compressed.
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of RDB file"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_length_encoding(&mut self) -> Result<Length> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(Length::Len((first & 0x3F) as u64)),
+            1 => {
+                let second = self.read_u8()?;
+                Ok(Length::Len((((first & 0x3F) as u64) << 8) | second as u64))
+            }
+            2 => match first {
+                0x80 => Ok(Length::Len(
+                    u32::from_be_bytes(self.read_bytes(4)?.try_into()?) as u64,
+                )),
+                0x81 => Ok(Length::Len(u64::from_be_bytes(
+                    self.read_bytes(8)?.try_into()?,
+                ))),
+                _ => Err(anyhow::anyhow!("invalid length encoding {first:#x}")),
+            },
+            _ => Ok(Length::Encoded(first & 0x3F)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(anyhow::anyhow!("expected a length, got an encoded string")),
+        }
+    }
+
+    fn read_utf8(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_string()?).to_string())
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<Data> {
+        match value_type {
+            TYPE_STRING => Ok(Data::String(self.read_utf8()?)),
+            TYPE_LIST => {
+                let len = self.read_length()?;
+                let list = (0..len).map(|_| self.read_utf8()).collect::<Result<_>>()?;
+                Ok(Data::List(list))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = self.read_length()?;
+                    let node = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => {
+                            list.push_back(String::from_utf8_lossy(&node).to_string())
+                        }
+                        QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&node)?),
+                        _ => return Err(anyhow::anyhow!("invalid quicklist node {container}")),
+                    }
+                }
+                Ok(Data::List(list))
+            }
+            TYPE_SET => {
+                let len = self.read_length()?;
+                let set = (0..len).map(|_| self.read_utf8()).collect::<Result<_>>()?;
+                Ok(Data::Set(set))
+            }
+            TYPE_SET_INTSET => Ok(Data::Set(intset_entries(&self.read_string()?)?)),
+            TYPE_SET_LISTPACK => Ok(Data::Set(
+                listpack_entries(&self.read_string()?)?
+                    .into_iter()
+                    .collect(),
+            )),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut set = SortedSet::new();
+                for _ in 0..len {
+                    let member = self.read_utf8()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.read_bytes(8)?.try_into()?)
+                    } else {
+                        self.read_text_score()?
+                    };
+                    set.insert(member, score);
+                }
+                Ok(Data::SortedSet(set))
+            }
+            TYPE_ZSET_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+                let mut set = SortedSet::new();
+                for pair in entries.chunks_exact(2) {
+                    set.insert(pair[0].clone(), pair[1].parse()?);
+                }
+                Ok(Data::SortedSet(set))
+            }
+            TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    let name = self.read_utf8()?;
+                    hash.insert(name, HashField::new(self.read_utf8()?));
+                }
+                Ok(Data::Hash(hash))
+            }
+            TYPE_HASH_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+                let hash = entries
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), HashField::new(pair[1].clone())))
+                    .collect();
+                Ok(Data::Hash(hash))
+            }
+            TYPE_HASH_METADATA => {
+                // Field expiries are stored relative to the earliest one,
+                // with zero meaning no expiry.
+                let min_expiry = u64::from_le_bytes(self.read_bytes(8)?.try_into()?);
+                let len = self.read_length()?;
+                let now = SystemTime::now();
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    let ttl = self.read_length()?;
+                    let name = self.read_utf8()?;
+                    let mut field = HashField::new(self.read_utf8()?);
+                    if ttl > 0 {
+                        let expiry = UNIX_EPOCH + Duration::from_millis(min_expiry + ttl - 1);
+                        if expiry < now {
+                            continue;
+                        }
+                        field.expiry = Some(expiry);
+                    }
+                    hash.insert(name, field);
+                }
+                Ok(Data::Hash(hash))
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                let mut stream = Stream::default();
+                let nodes = self.read_length()?;
+                for _ in 0..nodes {
+                    let master_id = stream_id(&self.read_string()?)?;
+                    let node = listpack_entries(&self.read_string()?)?;
+                    stream_node_entries(master_id, &node, &mut stream.entries)?;
+                }
+                // The number of entries, which we already know.
+                self.read_length()?;
+                stream.last_id = self.read_stream_id()?;
+                if value_type == TYPE_STREAM_LISTPACKS {
+                    stream.entries_added = stream.entries.len() as u64;
+                } else {
+                    // The first ID is implied by the entries.
+                    self.read_stream_id()?;
+                    stream.max_deleted_id = self.read_stream_id()?;
+                    stream.entries_added = self.read_length()?;
+                }
+                stream.groups = self.read_consumer_groups(value_type)?;
+                Ok(Data::Stream(stream))
+            }
+            t => Err(anyhow::anyhow!("unsupported RDB value type {t}")),
+        }
+    }
+
+    fn read_stream_id(&mut self) -> Result<StreamId> {
+        Ok(StreamId {
+            ms: self.read_length()?,
+            seq: self.read_length()?,
+        })
+    }
+
+    /// Reads the consumer groups of a stream. The group's pending entries
+    /// list holds the delivery state, and each consumer then lists the IDs of
+    /// the entries it owns.
+    fn read_consumer_groups(&mut self, value_type: u8) -> Result<BTreeMap<String, ConsumerGroup>> {
+        let mut groups = BTreeMap::new();
+        for _ in 0..self.read_length()? {
+            let name = self.read_utf8()?;
+            let mut group = ConsumerGroup {
+                last_id: self.read_stream_id()?,
+                ..Default::default()
+            };
+            if value_type != TYPE_STREAM_LISTPACKS {
+                // The number of entries read, used for the lag we don't report.
+                self.read_length()?;
+            }
+            let mut deliveries = BTreeMap::new();
+            for _ in 0..self.read_length()? {
+                let id = stream_id(self.read_bytes(16)?)?;
+                let delivery_time = u64::from_le_bytes(self.read_bytes(8)?.try_into()?);
+                deliveries.insert(id, (delivery_time, self.read_length()?));
+            }
+            for _ in 0..self.read_length()? {
+                let consumer = self.read_utf8()?;
+                let seen_time = u64::from_le_bytes(self.read_bytes(8)?.try_into()?);
+                if value_type == TYPE_STREAM_LISTPACKS_3 {
+                    // The time the consumer last read or claimed something.
+                    self.read_bytes(8)?;
+                }
+                for _ in 0..self.read_length()? {
+                    let id = stream_id(self.read_bytes(16)?)?;
+                    let (delivery_time, delivery_count) = deliveries
+                        .remove(&id)
+                        .ok_or_else(|| anyhow::anyhow!("consumer owns unknown entry {id}"))?;
+                    let pending = PendingEntry {
+                        consumer: consumer.clone(),
+                        delivery_time,
+                        delivery_count,
+                    };
+                    group.pending.insert(id, pending);
+                }
+                group.consumers.insert(consumer, seen_time);
+            }
+            groups.insert(name, group);
+        }
+        Ok(groups)
+    }
+
+    /// Reads a score of the old sorted set encoding, stored as text with
+    /// special lengths for infinities and NaN.
+    fn read_text_score(&mut self) -> Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => Ok(std::str::from_utf8(self.read_bytes(len as usize)?)?.parse()?),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => Ok(self.read_bytes(len as usize)?.to_vec()),
+            Length::Encoded(ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => Ok(i16::from_le_bytes(self.read_bytes(2)?.try_into()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_INT32) => Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                lzf_decompress(self.read_bytes(compressed_len)?, len)
+            }
+            Length::Encoded(enc) => Err(anyhow::anyhow!("unknown string encoding {enc}")),
+        }
+    }
+}
+
+/// Decodes the elements of a listpack, the compact encoding Redis uses for
+/// small collections.
+fn listpack_entries(data: &[u8]) -> Result<Vec<String>> {
+    let mut reader = Reader { data, pos: 0 };
+    // Total size in bytes and number of elements.
+    reader.read_bytes(6)?;
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let first = reader.read_u8()?;
+        let entry = match first {
+            0xFF => break,
+            b if b & 0x80 == 0 => (b & 0x7F).to_string(),
+            b if b & 0xC0 == 0x80 => {
+                let len = (b & 0x3F) as usize;
+                String::from_utf8_lossy(reader.read_bytes(len)?).to_string()
+            }
+            b if b & 0xE0 == 0xC0 => {
+                let value = (((b & 0x1F) as i64) << 8) | reader.read_u8()? as i64;
+                sign_extend(value, 13).to_string()
+            }
+            b if b & 0xF0 == 0xE0 => {
+                let len = (((b & 0x0F) as usize) << 8) | reader.read_u8()? as usize;
+                String::from_utf8_lossy(reader.read_bytes(len)?).to_string()
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?) as usize;
+                String::from_utf8_lossy(reader.read_bytes(len)?).to_string()
+            }
+            0xF1 => i16::from_le_bytes(reader.read_bytes(2)?.try_into()?).to_string(),
+            0xF2 => {
+                let bytes = reader.read_bytes(3)?;
+                let value = bytes[0] as i64 | (bytes[1] as i64) << 8 | (bytes[2] as i64) << 16;
+                sign_extend(value, 24).to_string()
+            }
+            0xF3 => i32::from_le_bytes(reader.read_bytes(4)?.try_into()?).to_string(),
+            0xF4 => i64::from_le_bytes(reader.read_bytes(8)?.try_into()?).to_string(),
+            b => return Err(anyhow::anyhow!("invalid listpack encoding {b:#x}")),
+        };
+        // Every entry is followed by its own length, used to walk backwards.
+        let entry_len = reader.pos - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        reader.read_bytes(backlen)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Encodes `entries` as a listpack, storing the ones that look like integers
+/// as such, like Redis does.
+fn listpack(entries: &[String]) -> Vec<u8> {
+    let mut buf = vec![0; 6];
+    for entry in entries {
+        let start = buf.len();
+        match entry.parse::<i64>() {
+            Ok(value) if value.to_string() == *entry => match value {
+                0..=127 => buf.push(value as u8),
+                -4096..=4095 => {
+                    buf.push(0xC0 | ((value >> 8) as u8 & 0x1F));
+                    buf.push(value as u8);
+                }
+                _ if i16::try_from(value).is_ok() => {
+                    buf.push(0xF1);
+                    buf.extend_from_slice(&(value as i16).to_le_bytes());
+                }
+                -8388608..=8388607 => {
+                    buf.push(0xF2);
+                    buf.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+                }
+                _ if i32::try_from(value).is_ok() => {
+                    buf.push(0xF3);
+                    buf.extend_from_slice(&(value as i32).to_le_bytes());
+                }
+                _ => {
+                    buf.push(0xF4);
+                    buf.extend_from_slice(&value.to_le_bytes());
+                }
+            },
+            _ => {
+                let len = entry.len();
+                if len < 1 << 6 {
+                    buf.push(0x80 | len as u8);
+                } else if len < 1 << 12 {
+                    buf.push(0xE0 | (len >> 8) as u8);
+                    buf.push(len as u8);
+                } else {
+                    buf.push(0xF0);
+                    buf.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                buf.extend_from_slice(entry.as_bytes());
+            }
+        }
+        // The entry length, written so that it can be read backwards: the
+        // high bit marks every byte but the first as a continuation.
+        let entry_len = buf.len() - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for i in (0..backlen).rev() {
+            let byte = ((entry_len >> (7 * i)) & 0x7F) as u8;
+            buf.push(if i == backlen - 1 { byte } else { byte | 0x80 });
+        }
+    }
+    buf.push(0xFF);
+    let total = buf.len() as u32;
+    buf[..4].copy_from_slice(&total.to_le_bytes());
+    // The element count saturates, readers then have to walk the listpack.
+    let count = entries.len().min(u16::MAX as usize) as u16;
+    buf[4..6].copy_from_slice(&count.to_le_bytes());
+    buf
+}
+
+/// Decodes a stream ID stored as a listpack node key: milliseconds and
+/// sequence number, both big endian.
+fn stream_id(bytes: &[u8]) -> Result<StreamId> {
+    if bytes.len() != 16 {
+        return Err(anyhow::anyhow!("invalid stream ID"));
+    }
+    Ok(StreamId {
+        ms: u64::from_be_bytes(bytes[..8].try_into()?),
+        seq: u64::from_be_bytes(bytes[8..].try_into()?),
+    })
+}
+
+fn stream_id_bytes(id: StreamId) -> Vec<u8> {
+    let mut bytes = id.ms.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&id.seq.to_be_bytes());
+    bytes
+}
+
+/// Decodes the entries of one listpack node of a stream. The node starts
+/// with a master entry holding the entry counts and the fields of the first
+/// entry, which later entries with the same fields leave out. Entry IDs are
+/// stored relative to `master_id`.
+fn stream_node_entries(
+    master_id: StreamId,
+    node: &[String],
+    entries: &mut BTreeMap<StreamId, Vec<(String, String)>>,
+) -> Result<()> {
+    let mut node = node.iter();
+    let mut next = || {
+        node.next()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("truncated stream listpack"))
+    };
+    let count = next()?.parse::<u64>()? + next()?.parse::<u64>()?;
+    let master_fields = (0..next()?.parse::<u64>()?)
+        .map(|_| next())
+        .collect::<Result<Vec<_>>>()?;
+    // The master entry terminator.
+    next()?;
+
+    for _ in 0..count {
+        let flags = next()?.parse::<i64>()?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(next()?.parse::<i64>()? as u64),
+            seq: master_id.seq.wrapping_add(next()?.parse::<i64>()? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?)))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            (0..next()?.parse::<u64>()?)
+                .map(|_| Ok((next()?, next()?)))
+                .collect::<Result<Vec<_>>>()?
+        };
+        // The number of listpack elements of the entry, to walk backwards.
+        next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the listpack elements of a stream node holding `chunk`, the
+/// inverse of `stream_node_entries`.
+fn stream_node(chunk: &[(&StreamId, &Vec<(String, String)>)]) -> Vec<String> {
+    let (master_id, master_entry) = chunk[0];
+    let mut node = vec![
+        chunk.len().to_string(),
+        "0".to_string(),
+        master_entry.len().to_string(),
+    ];
+    node.extend(master_entry.iter().map(|(field, _)| field.clone()));
+    node.push("0".to_string());
+
+    for (id, fields) in chunk {
+        let same_fields = fields.len() == master_entry.len()
+            && fields
+                .iter()
+                .zip(master_entry.iter())
+                .all(|((field, _), (master_field, _))| field == master_field);
+        let flags = if same_fields {
+            STREAM_ITEM_SAMEFIELDS
+        } else {
+            0
+        };
+        node.push(flags.to_string());
+        node.push((id.ms.wrapping_sub(master_id.ms) as i64).to_string());
+        node.push((id.seq.wrapping_sub(master_id.seq) as i64).to_string());
+        if same_fields {
+            node.extend(fields.iter().map(|(_, value)| value.clone()));
+            node.push((fields.len() + 3).to_string());
+        } else {
+            node.push(fields.len().to_string());
+            for (field, value) in fields.iter() {
+                node.push(field.clone());
+                node.push(value.clone());
+            }
+            node.push((fields.len() * 2 + 4).to_string());
+        }
+    }
+    node
+}
+
+/// Decodes an intset, the encoding Redis uses for small sets of integers.
+fn intset_entries(data: &[u8]) -> Result<ScanSet> {
+    let mut reader = Reader { data, pos: 0 };
+    let width = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?) as usize;
+    let len = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?);
+    (0..len)
+        .map(|_| {
+            let bytes = reader.read_bytes(width)?;
+            let value = match width {
+                2 => i16::from_le_bytes(bytes.try_into()?) as i64,
+                4 => i32::from_le_bytes(bytes.try_into()?) as i64,
+                8 => i64::from_le_bytes(bytes.try_into()?),
+                _ => return Err(anyhow::anyhow!("invalid intset encoding {width}")),
+            };
+            Ok(value.to_string())
+        })
+        .collect()
+}
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let truncated = || anyhow::anyhow!("truncated LZF data");
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // A literal run of ctrl + 1 bytes.
+            let run = input.get(i..i + ctrl + 1).ok_or_else(truncated)?;
+            output.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // A back reference into the already decompressed output.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(truncated)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(truncated)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1F) << 8) + low + 1;
+            let start = output
+                .len()
+                .checked_sub(back)
+                .ok_or_else(|| anyhow::anyhow!("invalid LZF back reference"))?;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(anyhow::anyhow!("LZF data decompressed to the wrong length"));
+    }
+    Ok(output)
+}
+
+/// Serializes the current contents of the store into the RDB format.
+pub fn encode(store: &Store) -> Vec<u8> {
+    encode_databases(store.snapshot())
+}
+
+fn encode_databases(databases: Vec<Vec<(String, RedisValue)>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    write_aux(&mut buf, "redis-ver", "7.4.0");
+    write_aux(&mut buf, "redis-bits", "64");
+    for (db, entries) in databases.into_iter().enumerate() {
+        if !entries.is_empty() {
+            encode_entries(&mut buf, db, entries);
+        }
+    }
+    buf.push(OPCODE_EOF);
+    let checksum = crc64(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn encode_entries(buf: &mut Vec<u8>, db: usize, entries: Vec<(String, RedisValue)>) {
+    buf.push(OPCODE_SELECTDB);
+    write_length(buf, db as u64);
+    buf.push(OPCODE_RESIZEDB);
+    write_length(buf, entries.len() as u64);
+    write_length(
+        buf,
+        entries.iter().filter(|(_, v)| v.expiry.is_some()).count() as u64,
+    );
+
+    for (key, data) in entries {
+        if let Some(expiry) = data.expiry {
+            buf.push(OPCODE_EXPIRETIME_MS);
+            buf.extend_from_slice(&unix_millis(expiry).to_le_bytes());
+        }
+        match &data.value {
+            Data::String(value) => {
+                buf.push(TYPE_STRING);
+                write_string(buf, key.as_bytes());
+                write_string(buf, value.as_bytes());
+            }
+            Data::List(list) => {
+                buf.push(TYPE_LIST);
+                write_string(buf, key.as_bytes());
+                write_length(buf, list.len() as u64);
+                for element in list {
+                    write_string(buf, element.as_bytes());
+                }
+            }
+            Data::Set(set) => {
+                buf.push(TYPE_SET);
+                write_string(buf, key.as_bytes());
+                write_length(buf, set.len() as u64);
+                for member in set {
+                    write_string(buf, member.as_bytes());
+                }
+            }
+            Data::SortedSet(set) => {
+                buf.push(TYPE_ZSET_2);
+                write_string(buf, key.as_bytes());
+                write_length(buf, set.len() as u64);
+                for (member, score) in set.iter() {
+                    write_string(buf, member.as_bytes());
+                    buf.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            Data::Stream(stream) => {
+                buf.push(TYPE_STREAM_LISTPACKS_3);
+                write_string(buf, key.as_bytes());
+                let entries: Vec<_> = stream.entries.iter().collect();
+                let nodes: Vec<_> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+                write_length(buf, nodes.len() as u64);
+                for node in nodes {
+                    write_string(buf, &stream_id_bytes(*node[0].0));
+                    write_string(buf, &listpack(&stream_node(node)));
+                }
+                write_length(buf, entries.len() as u64);
+                let first_id = entries.first().map_or(StreamId::MIN, |(id, _)| **id);
+                for id in [stream.last_id, first_id, stream.max_deleted_id] {
+                    write_length(buf, id.ms);
+                    write_length(buf, id.seq);
+                }
+                write_length(buf, stream.entries_added);
+                write_consumer_groups(buf, &stream.groups);
+            }
+            Data::Hash(hash) => {
+                let min_expiry = hash.min_expiry().map(unix_millis);
+                buf.push(if min_expiry.is_some() {
+                    TYPE_HASH_METADATA
+                } else {
+                    TYPE_HASH
+                });
+                write_string(buf, key.as_bytes());
+                if let Some(min_expiry) = min_expiry {
+                    buf.extend_from_slice(&min_expiry.to_le_bytes());
+                }
+                write_length(buf, hash.len() as u64);
+                for (name, field) in hash.iter() {
+                    if let Some(min_expiry) = min_expiry {
+                        let ttl = field
+                            .expiry
+                            .map_or(0, |expiry| unix_millis(expiry) - min_expiry + 1);
+                        write_length(buf, ttl);
+                    }
+                    write_string(buf, name.as_bytes());
+                    write_string(buf, field.value.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// CRC-64/Jones as used by Redis for RDB checksums.
+fn crc64(data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+    let mut crc = 0u64;
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn write_aux(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.push(OPCODE_AUX);
+    write_string(buf, key.as_bytes());
+    write_string(buf, value.as_bytes());
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn write_consumer_groups(buf: &mut Vec<u8>, groups: &BTreeMap<String, ConsumerGroup>) {
+    write_length(buf, groups.len() as u64);
+    for (name, group) in groups {
+        write_string(buf, name.as_bytes());
+        write_length(buf, group.last_id.ms);
+        write_length(buf, group.last_id.seq);
+        // The number of entries read is unknown, which Redis stores as -1.
+        write_length(buf, u64::MAX);
+        write_length(buf, group.pending.len() as u64);
+        for (id, pending) in &group.pending {
+            buf.extend_from_slice(&stream_id_bytes(*id));
+            buf.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            write_length(buf, pending.delivery_count);
+        }
+        write_length(buf, group.consumers.len() as u64);
+        for (consumer, seen_time) in &group.consumers {
+            write_string(buf, consumer.as_bytes());
+            // Seen and active time, the latter of which we don't track.
+            buf.extend_from_slice(&seen_time.to_le_bytes());
+            buf.extend_from_slice(&seen_time.to_le_bytes());
+            let owned: Vec<&StreamId> = group
+                .pending
+                .iter()
+                .filter(|(_, pending)| pending.consumer == *consumer)
+                .map(|(id, _)| id)
+                .collect();
+            write_length(buf, owned.len() as u64);
+            for id in owned {
+                buf.extend_from_slice(&stream_id_bytes(*id));
+            }
+        }
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.push(0x40 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty dump written by Redis 7.2.0, the one it sends to replicas
+    /// on a full resynchronization when it has no keys.
+    const REDIS_EMPTY_DUMP: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn read_value(value_type: u8, data: &[u8]) -> Data {
+        let mut reader = Reader { data, pos: 0 };
+        let value = reader.read_value(value_type).unwrap();
+        assert_eq!(reader.pos, data.len());
+        value
+    }
+
+    /// Renders a value with its collections sorted, to compare values
+    /// regardless of the order hash maps iterate in.
+    fn describe(value: &RedisValue) -> String {
+        let expiry = value.expiry.map(unix_millis);
+        let data = match &value.value {
+            Data::String(value) => format!("string {value:?}"),
+            Data::List(list) => format!("list {list:?}"),
+            Data::Set(set) => {
+                let mut members: Vec<_> = set.iter().collect();
+                members.sort();
+                format!("set {members:?}")
+            }
+            Data::SortedSet(set) => format!("zset {:?}", set.iter().collect::<Vec<_>>()),
+            Data::Hash(hash) => {
+                let mut fields: Vec<_> = hash
+                    .iter()
+                    .map(|(name, field)| (name, &field.value, field.expiry.map(unix_millis)))
+                    .collect();
+                fields.sort();
+                format!("hash {fields:?}")
+            }
+            Data::Stream(stream) => {
+                let groups: Vec<_> = stream
+                    .groups
+                    .iter()
+                    .map(|(name, group)| {
+                        let pending: Vec<_> = group
+                            .pending
+                            .iter()
+                            .map(|(id, entry)| (*id, entry.consumer.clone(), entry.delivery_count))
+                            .collect();
+                        (name, group.last_id, pending, &group.consumers)
+                    })
+                    .collect();
+                format!(
+                    "stream {:?} {} {} {} {groups:?}",
+                    stream.entries, stream.last_id, stream.max_deleted_id, stream.entries_added
+                )
+            }
+        };
+        format!("{data} expiry {expiry:?}")
+    }
+
+    fn describe_databases(databases: &[Vec<(String, RedisValue)>]) -> Vec<Vec<(String, String)>> {
+        databases
+            .iter()
+            .map(|entries| {
+                let mut described: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), describe(value)))
+                    .collect();
+                described.sort();
+                described
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        // The check value from Redis' own crc64 test.
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(b""), 0);
+    }
+
+    #[test]
+    fn decodes_a_dump_written_by_redis() {
+        let data = hex(REDIS_EMPTY_DUMP);
+        let (body, checksum) = data.split_at(data.len() - 8);
+        assert_eq!(crc64(body).to_le_bytes(), checksum);
+        assert!(decode(&data, 16).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_a_corrupted_dump() {
+        let mut data = hex(REDIS_EMPTY_DUMP);
+        // The first digit of the Redis version.
+        data[21] ^= 1;
+        assert!(decode(&data, 16).is_err());
+    }
+
+    #[test]
+    fn listpack_round_trip() {
+        let mut entries = strings(&[
+            "0",
+            "127",
+            "128",
+            "-1",
+            "4095",
+            "-4096",
+            "4096",
+            "32767",
+            "-32768",
+            "32768",
+            "8388607",
+            "-8388608",
+            "8388608",
+            "2147483647",
+            "-2147483648",
+            "2147483648",
+            "9223372036854775807",
+            "-9223372036854775808",
+            // Not stored as integers, as they would not read back the same.
+            "007",
+            "+1",
+            "1.5",
+            "",
+            "héllo",
+        ]);
+        for len in [63, 64, 4095, 4096, 20_000] {
+            entries.push("x".repeat(len));
+        }
+        assert_eq!(listpack_entries(&listpack(&entries)).unwrap(), entries);
+        assert_eq!(
+            listpack_entries(&listpack(&[])).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn intset_decodes_every_width() {
+        fn intset(width: usize, values: &[i64]) -> Vec<u8> {
+            let mut data = (width as u32).to_le_bytes().to_vec();
+            data.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for value in values {
+                data.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+            data
+        }
+        let cases: [(usize, &[i64]); 3] = [
+            (2, &[-32768, -1, 0, 7, 32767]),
+            (4, &[-2147483648, -40000, 40000, 2147483647]),
+            (8, &[i64::MIN, -1, 1 << 40, i64::MAX]),
+        ];
+        for (width, values) in cases {
+            let set = intset_entries(&intset(width, values)).unwrap();
+            let mut members: Vec<i64> = set.iter().map(|member| member.parse().unwrap()).collect();
+            members.sort();
+            assert_eq!(members, values);
+        }
+        assert!(intset_entries(&intset(3, &[1])).is_err());
+    }
+
+    #[test]
+    fn quicklist_decodes_packed_and_plain_nodes() {
+        let mut data = Vec::new();
+        write_length(&mut data, 3);
+        write_length(&mut data, QUICKLIST_NODE_PACKED);
+        write_string(&mut data, &listpack(&strings(&["a", "1", "-300"])));
+        write_length(&mut data, QUICKLIST_NODE_PLAIN);
+        write_string(&mut data, "large element".as_bytes());
+        write_length(&mut data, QUICKLIST_NODE_PACKED);
+        write_string(&mut data, &listpack(&strings(&["z"])));
+        match read_value(TYPE_LIST_QUICKLIST_2, &data) {
+            Data::List(list) => assert_eq!(list, ["a", "1", "-300", "large element", "z"]),
+            _ => panic!("expected a list"),
+        }
+    }
+
+    #[test]
+    fn lzf_decompresses_literals_and_back_references() {
+        // "abc", then 9 bytes copied from 3 back, which needs the extra
+        // length byte, then 3 from 1 back and a final literal.
+        let compressed = [
+            0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02, 0x20, 0x00, 0x00, b'!',
+        ];
+        let expected = b"abcabcabcabcccc!";
+        assert_eq!(
+            lzf_decompress(&compressed, expected.len()).unwrap(),
+            expected
+        );
+
+        // As a string in the file: the encoding, both lengths and the data.
+        let mut data = vec![0xC0 | ENC_LZF];
+        write_length(&mut data, compressed.len() as u64);
+        write_length(&mut data, expected.len() as u64);
+        data.extend_from_slice(&compressed);
+        match read_value(TYPE_STRING, &data) {
+            Data::String(value) => assert_eq!(value.as_bytes(), expected),
+            _ => panic!("expected a string"),
+        }
+
+        assert!(lzf_decompress(&compressed, expected.len() + 1).is_err());
+        assert!(lzf_decompress(&compressed[..5], 12).is_err());
+        // A back reference before the start of the output.
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let now = unix_millis(SystemTime::now());
+        let later = UNIX_EPOCH + Duration::from_millis(now + 60_000);
+        let value = |data| RedisValue::new(data);
+
+        let mut hash = Hash::new();
+        hash.insert("name".to_string(), HashField::new("value".to_string()));
+        let mut expiring = hash.clone();
+        expiring.insert("temp".to_string(), HashField::new("1".to_string()));
+        expiring.set_expiry("temp", Some(later));
+
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), 1.5);
+        zset.insert("b".to_string(), -2.0);
+        zset.insert("c".to_string(), f64::INFINITY);
+
+        let mut stream = Stream::default();
+        for seq in 0..250 {
+            let id = StreamId { ms: 1000, seq };
+            stream
+                .entries
+                .insert(id, vec![("field".to_string(), seq.to_string())]);
+            stream.last_id = id;
+        }
+        stream.entries_added = 251;
+        stream.max_deleted_id = StreamId { ms: 1000, seq: 250 };
+        let mut group = ConsumerGroup {
+            last_id: StreamId { ms: 1000, seq: 1 },
+            ..ConsumerGroup::default()
+        };
+        group.consumers.insert("alice".to_string(), now);
+        group.pending.insert(
+            StreamId { ms: 1000, seq: 1 },
+            PendingEntry {
+                consumer: "alice".to_string(),
+                delivery_time: now,
+                delivery_count: 2,
+            },
+        );
+        stream.groups.insert("readers".to_string(), group);
+
+        let databases = vec![
+            vec![
+                (
+                    "string".to_string(),
+                    value(Data::String("hello".to_string())),
+                ),
+                ("number".to_string(), value(Data::String("-42".to_string()))),
+                (
+                    "expiring".to_string(),
+                    RedisValue {
+                        value: Data::String("soon".to_string()),
+                        expiry: Some(later),
+                    },
+                ),
+                (
+                    "list".to_string(),
+                    value(Data::List(strings(&["x", "1", "y"]).into())),
+                ),
+                (
+                    "set".to_string(),
+                    value(Data::Set(strings(&["m", "2", "n"]).into_iter().collect())),
+                ),
+                ("zset".to_string(), value(Data::SortedSet(zset))),
+                ("hash".to_string(), value(Data::Hash(hash))),
+                ("fields".to_string(), value(Data::Hash(expiring))),
+                ("stream".to_string(), value(Data::Stream(stream))),
+            ],
+            Vec::new(),
+            vec![("other".to_string(), value(Data::String("db 2".to_string())))],
+        ];
+        let decoded = decode(&encode_databases(databases.clone()), 16).unwrap();
+        assert_eq!(describe_databases(&decoded), describe_databases(&databases));
+    }
+
+    #[test]
+    fn skips_expired_keys() {
+        let expired = RedisValue {
+            value: Data::String("gone".to_string()),
+            expiry: Some(UNIX_EPOCH + Duration::from_secs(1)),
+        };
+        let databases = vec![vec![("old".to_string(), expired)]];
+        let decoded = decode(&encode_databases(databases), 16).unwrap();
+        assert!(decoded.iter().all(|entries| entries.is_empty()));
+    }
+
+    #[test]
+    fn skips_hashes_whose_fields_all_expired() {
+        let past = UNIX_EPOCH + Duration::from_secs(1);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let mut expired = Hash::new();
+        let mut partly = Hash::new();
+        for name in ["a", "b"] {
+            expired.insert(name.to_string(), HashField::new("1".to_string()));
+            expired.set_expiry(name, Some(past));
+            partly.insert(name.to_string(), HashField::new("1".to_string()));
+        }
+        partly.set_expiry("a", Some(past));
+        partly.set_expiry("b", Some(later));
+        let databases = vec![vec![
+            ("expired".to_string(), RedisValue::new(Data::Hash(expired))),
+            ("partly".to_string(), RedisValue::new(Data::Hash(partly))),
+        ]];
+        let decoded = decode(&encode_databases(databases), 16).unwrap();
+        let keys: Vec<_> = decoded[0].iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["partly"]);
+        match &decoded[0][0].1.value {
+            Data::Hash(hash) => {
+                assert!(!hash.contains_key("a"));
+                assert!(hash.contains_key("b"));
+            }
+            _ => panic!("expected a hash"),
+        }
+    }
+
+    #[test]
+    fn rejects_databases_past_the_configured_count() {
+        let value = RedisValue::new(Data::String("value".to_string()));
+        let mut databases = vec![Vec::new(); 3];
+        databases[2].push(("key".to_string(), value));
+        let data = encode_databases(databases);
+        assert_eq!(decode(&data, 3).unwrap()[2].len(), 1);
+        assert!(decode(&data, 2).is_err());
+    }
+}
@@ -0,0 +1,542 @@
+use anyhow::Result;
+use resp::{encode_slice, Decoder, Value};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    aof::{self, Aof},
+    config::Config,
+    db::Store,
+    error::CommandError,
+    execute_command, extract_command, is_write_command, keys, parse_int, rdb, unpack_bulk_string,
+    REP_ID,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const REWRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+/// Bytes queued for a replica at most. A replica that falls further behind
+/// is dropped, like Redis does when a replica exceeds its output buffer limit.
+const REPLICA_QUEUE_LIMIT: usize = 256 * 1024 * 1024;
+
+struct Replica {
+    id: usize,
+    /// The connection, kept to shut it down when the replica is dropped.
+    stream: TcpStream,
+    /// Queues bytes for the thread that writes them to the replica, so that
+    /// a slow replica never holds up the clients propagating writes.
+    sender: Sender<Vec<u8>>,
+    /// The number of queued bytes not written yet.
+    queued: Arc<AtomicUsize>,
+    ack_offset: u64,
+}
+
+struct State {
+    replicas: Vec<Replica>,
+    next_id: usize,
+    /// The number of bytes propagated to replicas.
+    offset: u64,
+    /// On a replica, the number of bytes processed from the master. Writes
+    /// made on the replica itself only advance `offset`.
+    processed_offset: u64,
+    /// The most recently propagated bytes, kept so that a reconnecting replica
+    /// can continue from its offset instead of doing a full resync.
+    backlog: VecDeque<u8>,
+    /// On a replica, the replication id of the master it last synced with.
+    master_replid: Option<String>,
+    /// On a master, the database the last propagated `SELECT` switched to,
+    /// so that `SELECT` is only propagated when the database changes.
+    selected_db: Option<usize>,
+}
+
+impl State {
+    /// Registers a replica that is sent `initial` and then every propagated
+    /// write, starting at `offset`.
+    fn add_replica(&mut self, stream: TcpStream, offset: u64, initial: Vec<u8>) -> Result<usize> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (sender, receiver) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let writer = stream.try_clone()?;
+        let written = queued.clone();
+        thread::spawn(move || feed_replica(writer, initial, receiver, &written));
+        self.replicas.push(Replica {
+            id,
+            stream,
+            sender,
+            queued,
+            ack_offset: offset,
+        });
+        Ok(id)
+    }
+
+    /// Offset of the first byte still held in the backlog.
+    fn backlog_start(&self) -> u64 {
+        self.offset - self.backlog.len() as u64
+    }
+}
+
+/// Replication state shared by all client threads: the connected replicas and
+/// the replication offset.
+#[derive(Clone)]
+pub struct Replication {
+    config: Config,
+    state: Arc<Mutex<State>>,
+    acked: Arc<Condvar>,
+}
+
+impl Replication {
+    pub fn new(config: Config) -> Self {
+        Replication {
+            config,
+            state: Arc::new(Mutex::new(State {
+                replicas: Vec::new(),
+                next_id: 0,
+                offset: 0,
+                processed_offset: 0,
+                backlog: VecDeque::new(),
+                master_replid: None,
+                selected_db: None,
+            })),
+            acked: Arc::new(Condvar::new()),
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    pub fn replica_count(&self) -> usize {
+        self.state.lock().unwrap().replicas.len()
+    }
+
+    /// Returns the configured backlog size, the offset of its first byte and
+    /// the number of bytes it currently holds.
+    pub fn backlog_info(&self) -> (usize, u64, usize) {
+        let state = self.state.lock().unwrap();
+        (
+            self.config.read().repl_backlog_size as usize,
+            state.backlog_start(),
+            state.backlog.len(),
+        )
+    }
+
+    /// Streams a write command run against database `db` to every registered
+    /// replica, preceded by a `SELECT` if the replicas are on another database.
+    /// Like in the append only file, relative expiries are sent as absolute
+    /// timestamps, so replicas and partial resyncs keep the master's deadline.
+    pub fn propagate(&self, db: usize, command: &Value) {
+        let mut state = self.state.lock().unwrap();
+        let mut bytes = Vec::new();
+        if state.selected_db != Some(db) {
+            bytes.extend(encode_slice(&["SELECT", &db.to_string()]));
+            state.selected_db = Some(db);
+        }
+        bytes.extend(aof::absolute_expiry(command).encode());
+        self.send(&mut state, &bytes);
+    }
+
+    /// Queues bytes for every registered replica and adds them to the
+    /// backlog, dropping the replicas whose connection has gone away or that
+    /// fell too far behind.
+    fn send(&self, state: &mut State, bytes: &[u8]) {
+        let backlog_size = self.config.read().repl_backlog_size as usize;
+        state.offset += bytes.len() as u64;
+        state.backlog.extend(bytes);
+        let excess = state.backlog.len().saturating_sub(backlog_size);
+        state.backlog.drain(..excess);
+        state.replicas.retain(|replica| {
+            let queued = replica.queued.fetch_add(bytes.len(), Ordering::SeqCst);
+            if queued + bytes.len() > REPLICA_QUEUE_LIMIT {
+                println!("dropping replica: too far behind");
+                // Also ends the thread writing to it.
+                let _ = replica.stream.shutdown(Shutdown::Both);
+                return false;
+            }
+            // Fails once the thread writing to the replica gave up.
+            replica.sender.send(bytes.to_vec()).is_ok()
+        });
+    }
+
+    /// Records a `REPLCONF ACK <offset>` received from a replica.
+    pub fn record_ack(&self, id: usize, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = offset;
+        }
+        self.acked.notify_all();
+    }
+
+    /// Blocks until `numreplicas` replicas acknowledged every write propagated
+    /// so far, or until `timeout` expires. Returns the number of replicas that did.
+    pub fn wait(&self, numreplicas: usize, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let target = self.offset();
+        let acked = |state: &State| {
+            state
+                .replicas
+                .iter()
+                .filter(|r| r.ack_offset >= target)
+                .count()
+        };
+
+        let count = acked(&self.state.lock().unwrap());
+        if count >= numreplicas {
+            return count;
+        }
+        self.send(
+            &mut self.state.lock().unwrap(),
+            &encode_slice(&["REPLCONF", "GETACK", "*"]),
+        );
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let count = acked(&state);
+            if count >= numreplicas {
+                return count;
+            }
+            state = match deadline {
+                None => self.acked.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return count;
+                    }
+                    self.acked.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    pub fn processed_offset(&self) -> u64 {
+        self.state.lock().unwrap().processed_offset
+    }
+
+    /// Advances the offset of a replica by the size of a command received from its master.
+    fn advance(&self, len: u64) {
+        self.state.lock().unwrap().processed_offset += len;
+    }
+
+    fn set_processed_offset(&self, offset: u64) {
+        self.state.lock().unwrap().processed_offset = offset;
+    }
+
+    fn master_replid(&self) -> Option<String> {
+        self.state.lock().unwrap().master_replid.clone()
+    }
+
+    fn set_master_replid(&self, replid: String) {
+        self.state.lock().unwrap().master_replid = Some(replid);
+    }
+}
+
+/// Answers `PSYNC <replid> <offset>` and registers the connection as a
+/// replica. If the requested offset is still covered by the backlog the
+/// replica gets `+CONTINUE` and only the missing bytes, otherwise
+/// `+FULLRESYNC` followed by an RDB snapshot of the store. Returns the id of
+/// the new replica.
+pub fn handle_psync(
+    args: Vec<Value>,
+    stream: &mut TcpStream,
+    store: &Store,
+    replication: &Replication,
+) -> Result<usize> {
+    let replid = args.first().map(unpack_bulk_string).transpose()?;
+    // Like Redis, replicas ask for the offset of the next byte they need,
+    // which is one past the bytes they processed.
+    let offset = args
+        .get(1)
+        .map(unpack_bulk_string)
+        .transpose()?
+        .and_then(|offset| offset.parse::<u64>().ok())
+        .and_then(|offset| offset.checked_sub(1));
+
+    // The state stays locked until the replica is registered, so no write can
+    // be propagated in between and missed by the new replica. Writes are
+    // ordered first, so none is in the snapshot and propagated again.
+    let _order = store.order_writes();
+    let mut state = replication.state.lock().unwrap();
+    if let Some(offset) = offset.filter(|_| replid.as_deref() == Some(REP_ID)) {
+        if offset >= state.backlog_start() && offset <= state.offset {
+            let skip = (offset - state.backlog_start()) as usize;
+            let mut reply = format!("+CONTINUE {}\r\n", REP_ID).into_bytes();
+            reply.extend(state.backlog.iter().skip(skip));
+            let missing = state.offset - offset;
+            let id = state.add_replica(stream.try_clone()?, offset, reply)?;
+            println!("replica continued at offset {offset}, sending {missing} bytes");
+            return Ok(id);
+        }
+    }
+
+    let snapshot = rdb::encode(store);
+    let offset = state.offset;
+    // The new replica starts out on database 0.
+    state.selected_db = None;
+    let mut reply = format!("+FULLRESYNC {} {}\r\n", REP_ID, offset).into_bytes();
+    reply.extend(format!("${}\r\n", snapshot.len()).as_bytes());
+    reply.extend(&snapshot);
+    let id = state.add_replica(stream.try_clone()?, offset, reply)?;
+    println!(
+        "replica registered, sending {} byte snapshot",
+        snapshot.len()
+    );
+    Ok(id)
+}
+
+/// Writes `initial` and then the bytes queued for a replica to its
+/// connection, until the replica is dropped or the connection fails.
+fn feed_replica(
+    mut stream: TcpStream,
+    initial: Vec<u8>,
+    receiver: Receiver<Vec<u8>>,
+    queued: &AtomicUsize,
+) {
+    let result = stream.write_all(&initial).and_then(|()| {
+        for bytes in receiver {
+            stream.write_all(&bytes)?;
+            queued.fetch_sub(bytes.len(), Ordering::SeqCst);
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        println!("dropping replica: {e}");
+        // Also ends the client thread reading from the connection.
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Handles `REPLCONF` on the master. `ACK` is sent by replicas and must not be
+/// answered, so `None` is returned for it.
+pub fn handle_replconf(
+    args: Vec<Value>,
+    replica_id: Option<usize>,
+    replication: &Replication,
+) -> Result<Option<Value>> {
+    let subcommand = match args.first() {
+        Some(v) => unpack_bulk_string(v)?.to_lowercase(),
+        None => {
+            return Ok(Some(Value::Error(
+                "wrong number of arguments for 'replconf' command".to_string(),
+            )))
+        }
+    };
+    if subcommand == "ack" {
+        let offset = args
+            .get(1)
+            .map(unpack_bulk_string)
+            .transpose()?
+            .and_then(|offset| offset.parse::<u64>().ok());
+        if let (Some(id), Some(offset)) = (replica_id, offset) {
+            replication.record_ack(id, offset);
+        }
+        return Ok(None);
+    }
+    Ok(Some(Value::String("OK".to_string())))
+}
+
+pub fn handle_wait(args: Vec<Value>, replication: &Replication) -> Result<Value> {
+    if args.len() != 2 {
+        return Ok(Value::Error(
+            "wrong number of arguments for 'wait' command".to_string(),
+        ));
+    }
+    if replication.config.read().replicaof.is_some() {
+        return Err(CommandError::WaitOnReplica.into());
+    }
+    let numreplicas = unpack_bulk_string(&args[0])?
+        .parse::<usize>()
+        .map_err(|_| CommandError::NotInteger)?;
+    let timeout = parse_int(&unpack_bulk_string(&args[1])?)?;
+    if timeout < 0 {
+        return Err(CommandError::NegativeTimeout.into());
+    }
+    let timeout = timeout as u64;
+    let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+    let count = replication.wait(numreplicas, timeout);
+    Ok(Value::Integer(count as i64))
+}
+
+/// Accepts both `host port` (as passed by `redis-server --replicaof`) and `host:port`.
+pub fn parse_master_addr(spec: &str) -> String {
+    match spec.split_once(' ') {
+        Some((host, port)) => format!("{}:{}", host.trim(), port.trim()),
+        None => spec.to_string(),
+    }
+}
+
+/// Connects to the master, performs the replication handshake and keeps the
+/// link alive on a dedicated thread, reconnecting whenever it drops.
+pub fn start_replica(
+    master: String,
+    port: u16,
+    mut store: Store,
+    replication: Replication,
+    aof: Aof,
+) -> thread::JoinHandle<()> {
+    // The database selected by the master's `SELECT` is kept across
+    // reconnects, as a partial resync continues where the stream left off.
+    thread::spawn(move || loop {
+        println!("Connecting to master at {}", master);
+        match run_replica(&master, port, &mut store, &replication, &aof) {
+            Ok(()) => println!("master {} closed the replication link", master),
+            Err(e) => println!("replication link to {} failed: {}", master, e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    })
+}
+
+fn run_replica(
+    master: &str,
+    port: u16,
+    store: &mut Store,
+    replication: &Replication,
+    aof: &Aof,
+) -> Result<()> {
+    let stream = TcpStream::connect(master)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    send_command(&mut writer, &mut reader, &["PING"], "PONG")?;
+    send_command(
+        &mut writer,
+        &mut reader,
+        &["REPLCONF", "listening-port", &port.to_string()],
+        "OK",
+    )?;
+    send_command(
+        &mut writer,
+        &mut reader,
+        &["REPLCONF", "capa", "psync2"],
+        "OK",
+    )?;
+
+    let (replid, offset) = match replication.master_replid() {
+        Some(replid) => (replid, (replication.processed_offset() + 1).to_string()),
+        None => ("?".to_string(), "-1".to_string()),
+    };
+    let reply = send_command(&mut writer, &mut reader, &["PSYNC", &replid, &offset], "")?;
+    let mut parts = reply.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("CONTINUE"), replid, _) => {
+            if let Some(replid) = replid {
+                replication.set_master_replid(replid.to_string());
+            }
+            println!("Partial resynchronization with master {} accepted", master);
+        }
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset
+                .parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("invalid FULLRESYNC reply: {}", reply))?;
+            let snapshot = read_snapshot(&mut reader)?;
+            let databases = rdb::decode(&snapshot, store.databases())?;
+            store.flush_all();
+            rdb::restore(store, databases)?;
+            store.select(0);
+            // The log still holds the history from before the sync, which the
+            // commands streamed from now on must not be replayed on top of.
+            // A rewrite that is already running snapshotted the old dataset,
+            // so wait for it and start another one.
+            if aof.enabled() {
+                while !aof.rewrite(store) {
+                    thread::sleep(REWRITE_RETRY_DELAY);
+                }
+            }
+            replication.set_master_replid(replid.to_string());
+            replication.set_processed_offset(offset);
+            println!(
+                "Handshake with master {} complete, received {} byte snapshot",
+                master,
+                snapshot.len()
+            );
+        }
+        _ => return Err(anyhow::anyhow!("unexpected reply to PSYNC: {}", reply)),
+    }
+
+    // Commands streamed by the master are applied silently, without a reply.
+    // The only exception is `REPLCONF GETACK`, which is answered with the
+    // offset processed before it.
+    let mut decoder = Decoder::new(reader);
+    loop {
+        let value = match decoder.decode() {
+            Ok(value) => value,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let (command, args) = extract_command(&value)?;
+        let command = command.to_lowercase();
+        if command == "replconf" && is_getack(&args) {
+            let offset = replication.processed_offset().to_string();
+            writer.write_all(&encode_slice(&["REPLCONF", "ACK", &offset]))?;
+        } else if command == "select" {
+            if let Err(e) = keys::handle_select(args, store) {
+                println!("error applying command from master: {e}");
+            }
+        } else {
+            let order = store.order_writes();
+            match execute_command(&command, args, store) {
+                Ok(Value::Error(e)) => println!("error applying command from master: {e}"),
+                Ok(_) if is_write_command(&command) => aof.append(store.db(), &value),
+                Ok(_) => {}
+                Err(e) => println!("error applying command from master: {e}"),
+            }
+            drop(order);
+            // Clients blocked on this replica may be able to proceed now.
+            store.wake_blocked();
+        }
+        replication.advance(value.encode().len() as u64);
+    }
+}
+
+fn is_getack(args: &[Value]) -> bool {
+    matches!(args.first(), Some(Value::Bulk(s)) if s.eq_ignore_ascii_case("getack"))
+}
+
+/// Sends a command to the master and checks that the simple string reply
+/// starts with `expected`.
+fn send_command(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    command: &[&str],
+    expected: &str,
+) -> Result<String> {
+    writer.write_all(&encode_slice(command))?;
+    let line = read_line(reader)?;
+    match line.strip_prefix('+') {
+        Some(reply) if reply.starts_with(expected) => Ok(reply.to_string()),
+        _ => Err(anyhow::anyhow!(
+            "unexpected reply to {}: {}",
+            command[0],
+            line
+        )),
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(anyhow::anyhow!("master closed the connection"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Reads the `$<len>\r\n<bytes>` snapshot that follows `+FULLRESYNC`. Unlike a
+/// regular bulk string the payload is not terminated by CRLF.
+fn read_snapshot(reader: &mut BufReader<TcpStream>) -> Result<Vec<u8>> {
+    let line = read_line(reader)?;
+    let len = line
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid snapshot header: {}", line))?;
+    let mut snapshot = vec![0; len];
+    reader.read_exact(&mut snapshot)?;
+    Ok(snapshot)
+}
@@ -0,0 +1,255 @@
+use anyhow::Result;
+use resp::Value;
+use std::collections::HashSet;
+
+use crate::{
+    bulk_array,
+    db::{Data, Keyspace, RedisValue, Store},
+    error::CommandError,
+    keys::{scan_reply, ScanOptions},
+    parse_int, random_index, unpack_args,
+};
+
+/// `SADD key member [member ...]`
+pub fn handle_sadd(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("sadd").into());
+    }
+    let mut keyspace = store.lock();
+    let set = keyspace.set_or_create(&args[0])?;
+    let added = args[1..]
+        .iter()
+        .filter(|member| set.insert(member.to_string()))
+        .count();
+    Ok(Value::Integer(added as i64))
+}
+
+/// `SREM key member [member ...]`
+pub fn handle_srem(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("srem").into());
+    }
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let set = match keyspace.set(key)? {
+        Some(set) => set,
+        None => return Ok(Value::Integer(0)),
+    };
+    let removed = args[1..].iter().filter(|member| set.remove(member)).count();
+    keyspace.remove_if_empty(key);
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `SMEMBERS key`
+pub fn handle_smembers(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("smembers").into());
+    }
+    let members = members(&mut store.lock(), &args[0])?;
+    Ok(bulk_set(members))
+}
+
+/// `SISMEMBER key member`
+pub fn handle_sismember(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("sismember").into());
+    }
+    let member = store
+        .lock()
+        .set(&args[0])?
+        .is_some_and(|set| set.contains(&args[1]));
+    Ok(Value::Integer(member as i64))
+}
+
+/// `SCARD key`
+pub fn handle_scard(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("scard").into());
+    }
+    let len = store.lock().set(&args[0])?.map_or(0, |set| set.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `SSCAN key cursor [MATCH pattern] [COUNT count]`
+pub fn handle_sscan(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("sscan").into());
+    }
+    let options = ScanOptions::parse(&args[1], &args[2..], "")?;
+    let mut keyspace = store.lock();
+    let set = keyspace.set(&args[0])?;
+    let (next, members) = set.map_or((0, Vec::new()), |set| {
+        set.scan(options.cursor, options.count)
+    });
+    let page = members
+        .into_iter()
+        .filter(|member| options.matches(member))
+        .map(|member| Value::Bulk(member.clone()))
+        .collect();
+    Ok(scan_reply(next, page))
+}
+
+/// `SINTER` / `SUNION` / `SDIFF key [key ...]`
+pub fn handle_combine(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let result = combine(&mut store.lock(), command, &args)?;
+    Ok(bulk_set(result))
+}
+
+/// `SINTERSTORE` / `SUNIONSTORE` / `SDIFFSTORE destination key [key ...]`
+///
+/// Replaces `destination` with the result, or deletes it if the result is empty.
+pub fn handle_combine_store(
+    args: Vec<Value>,
+    store: &Store,
+    command: &'static str,
+) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let mut keyspace = store.lock();
+    let result = combine(&mut keyspace, command, &args[1..])?;
+    let len = result.len();
+    keyspace.insert(
+        args[0].clone(),
+        RedisValue::new(Data::Set(result.into_iter().collect())),
+    );
+    keyspace.remove_if_empty(&args[0]);
+    Ok(Value::Integer(len as i64))
+}
+
+/// `SRANDMEMBER key [count]`
+///
+/// A positive count returns distinct members, a negative one may return the
+/// same member several times.
+pub fn handle_srandmember(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() || args.len() > 2 {
+        return Err(CommandError::WrongArity("srandmember").into());
+    }
+    let count = args.get(1).map(|count| parse_int(count)).transpose()?;
+    let mut keyspace = store.lock();
+    let set = keyspace.set(&args[0])?;
+    let members: Vec<&String> = set.iter().flat_map(|set| set.iter()).collect();
+    match count {
+        None if members.is_empty() => Ok(Value::Null),
+        None => Ok(Value::Bulk(members[random_index(members.len())].clone())),
+        Some(count) if count < 0 && !members.is_empty() => {
+            let picked = (0..count.unsigned_abs())
+                .map(|_| Value::Bulk(members[random_index(members.len())].clone()))
+                .collect();
+            Ok(Value::Array(picked))
+        }
+        Some(count) => {
+            let picked = sample(members, count.max(0) as usize);
+            Ok(Value::Array(
+                picked.into_iter().cloned().map(Value::Bulk).collect(),
+            ))
+        }
+    }
+}
+
+/// `SPOP key [count]`
+///
+/// Besides the reply, returns the `SREM` of the popped members that has to be
+/// propagated, since replaying `SPOP` would pick different members.
+pub fn handle_spop(args: Vec<Value>, store: &Store) -> Result<(Value, Vec<Value>)> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() || args.len() > 2 {
+        return Err(CommandError::WrongArity("spop").into());
+    }
+    let count = match args.get(1) {
+        Some(count) => {
+            let count = parse_int(count)?;
+            if count < 0 {
+                return Err(CommandError::NotPositive.into());
+            }
+            Some(count as usize)
+        }
+        None => None,
+    };
+
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let set = match keyspace.set(key)? {
+        Some(set) => set,
+        None if count.is_some() => return Ok((Value::Array(Vec::new()), Vec::new())),
+        None => return Ok((Value::Null, Vec::new())),
+    };
+    let popped: Vec<String> = sample(set.iter().cloned().collect(), count.unwrap_or(1));
+    for member in &popped {
+        set.remove(member);
+    }
+    keyspace.remove_if_empty(key);
+
+    let mut writes = Vec::new();
+    if !popped.is_empty() {
+        let mut command = vec!["SREM".to_string(), key.clone()];
+        command.extend(popped.iter().cloned());
+        writes.push(bulk_array(command));
+    }
+    let reply = match count {
+        Some(_) => Value::Array(popped.into_iter().map(Value::Bulk).collect()),
+        None => popped
+            .into_iter()
+            .next()
+            .map(Value::Bulk)
+            .unwrap_or(Value::Null),
+    };
+    Ok((reply, writes))
+}
+
+/// Returns a copy of the set at `key`, which is empty if the key does not exist.
+fn members(keyspace: &mut Keyspace, key: &str) -> Result<HashSet<String>, CommandError> {
+    Ok(keyspace
+        .set(key)?
+        .map(|set| set.iter().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Computes the intersection, union or difference of the sets at `keys`.
+/// Missing keys count as empty sets.
+fn combine(
+    keyspace: &mut Keyspace,
+    command: &str,
+    keys: &[String],
+) -> Result<HashSet<String>, CommandError> {
+    let mut result = members(keyspace, &keys[0])?;
+    for key in &keys[1..] {
+        let other = members(keyspace, key)?;
+        if command.starts_with("sinter") {
+            result.retain(|member| other.contains(member));
+        } else if command.starts_with("sunion") {
+            result.extend(other);
+        } else {
+            result.retain(|member| !other.contains(member));
+        }
+    }
+    Ok(result)
+}
+
+/// Picks up to `count` distinct elements at random.
+fn sample<T>(mut elements: Vec<T>, count: usize) -> Vec<T> {
+    let count = count.min(elements.len());
+    // A partial Fisher-Yates shuffle moves the picked elements to the front.
+    for i in 0..count {
+        let j = i + random_index(elements.len() - i);
+        elements.swap(i, j);
+    }
+    elements.truncate(count);
+    elements
+}
+
+fn bulk_set(set: HashSet<String>) -> Value {
+    Value::Array(set.into_iter().map(Value::Bulk).collect())
+}
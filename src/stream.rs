@@ -0,0 +1,1009 @@
+use anyhow::Result;
+use resp::Value;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bulk_array,
+    db::{ConsumerGroup, Keyspace, PendingEntry, Store, Stream, StreamId, StreamTrim},
+    error::CommandError,
+    parse_int, unpack_args,
+};
+
+/// How many pending entries `XAUTOCLAIM` claims at most when no `COUNT` is given.
+const DEFAULT_AUTOCLAIM_COUNT: usize = 100;
+/// Pending entries `XAUTOCLAIM` looks at per entry it is asked to claim.
+const AUTOCLAIM_ATTEMPTS_FACTOR: usize = 10;
+
+/// `XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] *|id field value [field value ...]`
+///
+/// Besides the reply, returns the `XADD` with the ID that was actually used,
+/// since replaying an auto-generated ID would produce a different one.
+pub fn handle_xadd(args: Vec<Value>, store: &Store) -> Result<(Value, Vec<Value>)> {
+    let mut args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("xadd").into());
+    }
+    let key = args[0].clone();
+    let mut i = 1;
+    let nomkstream = args[i].eq_ignore_ascii_case("nomkstream");
+    if nomkstream {
+        i += 1;
+    }
+    let trim = parse_trim(&args, &mut i)?;
+    let fields = args.get(i + 1..).unwrap_or_default();
+    if fields.is_empty() || fields.len() % 2 != 0 {
+        return Err(CommandError::WrongArity("xadd").into());
+    }
+
+    let mut keyspace = store.lock();
+    let last_id = match keyspace.stream(&key)? {
+        Some(stream) => stream.last_id,
+        None if nomkstream => return Ok((Value::Null, Vec::new())),
+        None => StreamId::MIN,
+    };
+    // Validate the ID before creating the stream, so a rejected entry does
+    // not leave an empty stream behind.
+    let id = next_id(&args[i], last_id)?;
+    let stream = keyspace.stream_or_create(&key)?;
+    let entry = fields
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    stream.entries.insert(id, entry);
+    stream.last_id = id;
+    stream.entries_added += 1;
+    if let Some((trim, limit)) = trim {
+        stream.trim(&trim, limit);
+    }
+
+    args[i] = id.to_string();
+    let mut command = vec!["XADD".to_string()];
+    command.extend(args);
+    Ok((Value::Bulk(id.to_string()), vec![bulk_array(command)]))
+}
+
+/// `XLEN key`
+pub fn handle_xlen(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("xlen").into());
+    }
+    let len = store
+        .lock()
+        .stream(&args[0])?
+        .map_or(0, |stream| stream.entries.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `XRANGE key start end [COUNT count]` and `XREVRANGE key end start [COUNT count]`
+pub fn handle_xrange(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 && args.len() != 5 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let rev = command == "xrevrange";
+    let (start, end) = if rev {
+        (&args[2], &args[1])
+    } else {
+        (&args[1], &args[2])
+    };
+    let count = match args.get(3) {
+        Some(option) if option.eq_ignore_ascii_case("count") => {
+            parse_int(&args[4])?.max(0) as usize
+        }
+        Some(_) => return Err(CommandError::Syntax.into()),
+        None => usize::MAX,
+    };
+    let (start, end) = match (range_bound(start, true)?, range_bound(end, false)?) {
+        (Some(start), Some(end)) if start <= end => (start, end),
+        _ => return Ok(Value::Array(Vec::new())),
+    };
+
+    let mut keyspace = store.lock();
+    let stream = match keyspace.stream(&args[0])? {
+        Some(stream) => stream,
+        None => return Ok(Value::Array(Vec::new())),
+    };
+    let entries = stream.entries.range(start..=end);
+    let entries: Vec<Value> = if rev {
+        entries.rev().take(count).map(entry_reply).collect()
+    } else {
+        entries.take(count).map(entry_reply).collect()
+    };
+    Ok(Value::Array(entries))
+}
+
+/// `XTRIM key MAXLEN|MINID [=|~] threshold [LIMIT count]`
+pub fn handle_xtrim(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 {
+        return Err(CommandError::WrongArity("xtrim").into());
+    }
+    let mut i = 1;
+    let (trim, limit) = parse_trim(&args, &mut i)?.ok_or(CommandError::Syntax)?;
+    if i != args.len() {
+        return Err(CommandError::Syntax.into());
+    }
+    let removed = store
+        .lock()
+        .stream(&args[0])?
+        .map_or(0, |stream| stream.trim(&trim, limit));
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]`
+///
+/// Returns the entries after the given IDs. With `BLOCK`, waits until one of
+/// the streams has any, where `$` stands for the last ID at the time of the
+/// call. Zero blocks forever.
+pub fn handle_xread(
+    args: Vec<Value>,
+    store: &Store,
+    connected: &dyn Fn() -> bool,
+) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    let options = ReadOptions::parse(&args, "xread")?;
+    let ids = {
+        let mut keyspace = store.lock();
+        options
+            .keys
+            .iter()
+            .zip(&options.ids)
+            .map(|(key, id)| match id.as_str() {
+                "$" => Ok(keyspace
+                    .stream(key)?
+                    .map_or(StreamId::MIN, |stream| stream.last_id)),
+                ">" => Err(CommandError::GreaterIdOutsideGroup),
+                id => parse_id(id, 0),
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let read = |keyspace: &mut Keyspace| {
+        let mut streams = Vec::new();
+        for (key, id) in options.keys.iter().zip(&ids) {
+            let stream = match keyspace.stream(key)? {
+                Some(stream) => stream,
+                None => continue,
+            };
+            let entries: Vec<Value> = match id.next() {
+                Some(start) => stream
+                    .entries
+                    .range(start..)
+                    .take(options.count)
+                    .map(entry_reply)
+                    .collect(),
+                None => Vec::new(),
+            };
+            if !entries.is_empty() {
+                streams.push(stream_reply(key, entries));
+            }
+        }
+        Ok((!streams.is_empty()).then_some((Value::Array(streams), Vec::new())))
+    };
+    let reply = if options.block {
+        store.wait(options.deadline, connected, read)?
+    } else {
+        read(&mut store.lock())?.map(|(reply, _)| reply)
+    };
+    Ok(reply.unwrap_or(Value::NullArray))
+}
+
+/// `XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK]
+/// STREAMS key [key ...] id [id ...]`
+///
+/// `>` delivers entries that no consumer of the group has seen yet and adds
+/// them to the pending entries list, unless `NOACK` is given. Any other ID
+/// rereads the consumer's own pending entries after it. The deliveries are
+/// logged as `XCLAIM`s, which record them on replicas.
+pub fn handle_xreadgroup(
+    args: Vec<Value>,
+    store: &Store,
+    connected: &dyn Fn() -> bool,
+) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 || !args[0].eq_ignore_ascii_case("group") {
+        return Err(CommandError::Syntax.into());
+    }
+    let (group, consumer) = (&args[1], &args[2]);
+    let options = ReadOptions::parse(&args[3..], "xreadgroup")?;
+    // `None` stands for `>`.
+    let ids = options
+        .ids
+        .iter()
+        .map(|id| match id.as_str() {
+            ">" => Ok(None),
+            "$" => Err(CommandError::LastIdInGroup),
+            id => parse_id(id, 0).map(Some),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let read = |keyspace: &mut Keyspace| {
+        let now = now_millis();
+        let mut streams = Vec::new();
+        let mut writes = Vec::new();
+        for (key, id) in options.keys.iter().zip(&ids) {
+            let Stream {
+                entries, groups, ..
+            } = keyspace
+                .stream(key)?
+                .ok_or_else(|| CommandError::NoGroupToRead(key.clone(), group.clone()))?;
+            let state = groups
+                .get_mut(group)
+                .ok_or_else(|| CommandError::NoGroupToRead(key.clone(), group.clone()))?;
+            state.consumers.insert(consumer.clone(), now);
+
+            let after = match id {
+                Some(after) => *after,
+                None => {
+                    let delivered: Vec<(&StreamId, &Vec<(String, String)>)> =
+                        match state.last_id.next() {
+                            Some(start) => entries.range(start..).take(options.count).collect(),
+                            None => Vec::new(),
+                        };
+                    let ids: Vec<StreamId> = delivered.iter().map(|(id, _)| **id).collect();
+                    let last_id = match ids.last() {
+                        Some(&last_id) => last_id,
+                        None => continue,
+                    };
+                    state.last_id = last_id;
+                    if options.noack {
+                        writes.push(bulk_array(vec![
+                            "XGROUP".to_string(),
+                            "SETID".to_string(),
+                            key.clone(),
+                            group.clone(),
+                            last_id.to_string(),
+                        ]));
+                    } else {
+                        for &id in &ids {
+                            let pending = PendingEntry {
+                                consumer: consumer.clone(),
+                                delivery_time: now,
+                                delivery_count: 1,
+                            };
+                            state.pending.insert(id, pending);
+                        }
+                        let pending = &state.pending[&last_id];
+                        writes.push(bulk_array(claim_args(key, group, &ids, pending, last_id)));
+                    }
+                    let delivered = delivered.into_iter().map(entry_reply).collect();
+                    streams.push(stream_reply(key, delivered));
+                    continue;
+                }
+            };
+
+            // The history is returned even if it is empty, and includes
+            // entries that have been deleted from the stream since.
+            let history = match after.next() {
+                Some(start) => state
+                    .pending
+                    .range(start..)
+                    .filter(|(_, pending)| pending.consumer == *consumer)
+                    .take(options.count)
+                    .map(|(id, _)| match entries.get_key_value(id) {
+                        Some(entry) => entry_reply(entry),
+                        None => Value::Array(vec![Value::Bulk(id.to_string()), Value::NullArray]),
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            streams.push(stream_reply(key, history));
+        }
+        Ok((!streams.is_empty()).then_some((Value::Array(streams), writes)))
+    };
+    // Reading the history never blocks. Without blocking, a deadline that
+    // already passed reads once, still ordered with other writes.
+    let deadline = if options.block && ids.iter().all(Option::is_none) {
+        options.deadline
+    } else {
+        Some(Instant::now())
+    };
+    Ok(store
+        .wait(deadline, connected, read)?
+        .unwrap_or(Value::NullArray))
+}
+
+/// `XACK key group id [id ...]`
+pub fn handle_xack(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 {
+        return Err(CommandError::WrongArity("xack").into());
+    }
+    let ids = args[2..]
+        .iter()
+        .map(|id| parse_id(id, 0))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut keyspace = store.lock();
+    let acked = match keyspace
+        .stream(&args[0])?
+        .and_then(|stream| stream.groups.get_mut(&args[1]))
+    {
+        Some(group) => ids
+            .iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count(),
+        None => 0,
+    };
+    Ok(Value::Integer(acked as i64))
+}
+
+/// `XGROUP CREATE key group id|$ [MKSTREAM]`, `XGROUP SETID key group id|$`,
+/// `XGROUP DESTROY key group` and `XGROUP CREATECONSUMER|DELCONSUMER key group consumer`
+pub fn handle_xgroup(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    let subcommand = args.first().map(|s| s.to_lowercase()).unwrap_or_default();
+    let arity_ok = match subcommand.as_str() {
+        "create" => args.len() == 4 || args.len() == 5,
+        "setid" | "createconsumer" | "delconsumer" => args.len() == 4,
+        "destroy" => args.len() == 3,
+        _ => false,
+    };
+    if !arity_ok {
+        return Err(CommandError::UnknownSubcommand(
+            args.first().cloned().unwrap_or_default(),
+            "XGROUP",
+        )
+        .into());
+    }
+    let (key, group) = (&args[1], &args[2]);
+    // `None` stands for `$`, the last ID of the stream.
+    let id = match subcommand.as_str() {
+        "create" | "setid" if args[3] == "$" => None,
+        "create" | "setid" => Some(parse_id(&args[3], 0)?),
+        _ => None,
+    };
+
+    let mut keyspace = store.lock();
+    if subcommand == "create" {
+        let mkstream = match args.get(4) {
+            Some(option) if option.eq_ignore_ascii_case("mkstream") => true,
+            Some(_) => return Err(CommandError::Syntax.into()),
+            None => false,
+        };
+        if keyspace.stream(key)?.is_none() && !mkstream {
+            return Err(CommandError::GroupWithoutStream.into());
+        }
+        let stream = keyspace.stream_or_create(key)?;
+        if stream.groups.contains_key(group) {
+            return Err(CommandError::BusyGroup.into());
+        }
+        let state = ConsumerGroup {
+            last_id: id.unwrap_or(stream.last_id),
+            ..Default::default()
+        };
+        stream.groups.insert(group.clone(), state);
+        return Ok(Value::String("OK".to_string()));
+    }
+
+    let stream = keyspace
+        .stream(key)?
+        .ok_or(CommandError::GroupWithoutStream)?;
+    if subcommand == "destroy" {
+        let destroyed = stream.groups.remove(group).is_some();
+        return Ok(Value::Integer(destroyed as i64));
+    }
+    let last_id = stream.last_id;
+    let state = stream
+        .groups
+        .get_mut(group)
+        .ok_or_else(|| CommandError::NoGroupForKey(key.clone(), group.clone()))?;
+    match subcommand.as_str() {
+        "setid" => {
+            state.last_id = id.unwrap_or(last_id);
+            Ok(Value::String("OK".to_string()))
+        }
+        "createconsumer" => {
+            let created = !state.consumers.contains_key(&args[3]);
+            if created {
+                state.consumers.insert(args[3].clone(), now_millis());
+            }
+            Ok(Value::Integer(created as i64))
+        }
+        _ => {
+            // Deleting a consumer drops its pending entries too.
+            let consumer = &args[3];
+            state.consumers.remove(consumer);
+            let before = state.pending.len();
+            state
+                .pending
+                .retain(|_, pending| pending.consumer != *consumer);
+            Ok(Value::Integer((before - state.pending.len()) as i64))
+        }
+    }
+}
+
+/// `XPENDING key group [[IDLE min-idle-time] start end count [consumer]]`
+///
+/// Without a range, summarizes the pending entries list: its size, the
+/// smallest and largest ID and the number of entries per consumer.
+pub fn handle_xpending(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("xpending").into());
+    }
+    let mut i = 2;
+    let mut min_idle = 0;
+    if args
+        .get(i)
+        .is_some_and(|option| option.eq_ignore_ascii_case("idle"))
+    {
+        min_idle = parse_int(args.get(i + 1).ok_or(CommandError::Syntax)?)?.max(0) as u64;
+        i += 2;
+    }
+    let range = match args.len() - i.min(args.len()) {
+        0 if i == 2 => None,
+        3 | 4 => Some((
+            range_bound(&args[i], true)?,
+            range_bound(&args[i + 1], false)?,
+            parse_int(&args[i + 2])?.max(0) as usize,
+            args.get(i + 3),
+        )),
+        _ => return Err(CommandError::Syntax.into()),
+    };
+
+    let (key, group) = (&args[0], &args[1]);
+    let mut keyspace = store.lock();
+    let state = keyspace
+        .stream(key)?
+        .and_then(|stream| stream.groups.get(group))
+        .ok_or_else(|| CommandError::NoGroup(key.clone(), group.clone()))?;
+
+    let (start, end, count, consumer) = match range {
+        Some(range) => range,
+        None => {
+            let (first, last) = match (
+                state.pending.first_key_value(),
+                state.pending.last_key_value(),
+            ) {
+                (Some((first, _)), Some((last, _))) => (first, last),
+                _ => {
+                    return Ok(Value::Array(vec![
+                        Value::Integer(0),
+                        Value::Null,
+                        Value::Null,
+                        Value::NullArray,
+                    ]))
+                }
+            };
+            let mut per_consumer: BTreeMap<&str, usize> = BTreeMap::new();
+            for pending in state.pending.values() {
+                *per_consumer.entry(&pending.consumer).or_default() += 1;
+            }
+            let per_consumer = per_consumer
+                .into_iter()
+                .map(|(consumer, count)| bulk_array(vec![consumer.to_string(), count.to_string()]))
+                .collect();
+            return Ok(Value::Array(vec![
+                Value::Integer(state.pending.len() as i64),
+                Value::Bulk(first.to_string()),
+                Value::Bulk(last.to_string()),
+                Value::Array(per_consumer),
+            ]));
+        }
+    };
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) if start <= end => (start, end),
+        _ => return Ok(Value::Array(Vec::new())),
+    };
+    let now = now_millis();
+    let entries = state
+        .pending
+        .range(start..=end)
+        .filter(|(_, pending)| consumer.is_none_or(|consumer| pending.consumer == *consumer))
+        .filter(|(_, pending)| now.saturating_sub(pending.delivery_time) >= min_idle)
+        .take(count)
+        .map(|(id, pending)| {
+            Value::Array(vec![
+                Value::Bulk(id.to_string()),
+                Value::Bulk(pending.consumer.clone()),
+                Value::Integer(now.saturating_sub(pending.delivery_time) as i64),
+                Value::Integer(pending.delivery_count as i64),
+            ])
+        })
+        .collect();
+    Ok(Value::Array(entries))
+}
+
+/// `XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms]
+/// [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]`
+///
+/// Transfers pending entries idle for at least `min-idle-time` milliseconds
+/// to `consumer`. Besides the reply, returns the commands that record the
+/// outcome on replicas independently of their clock.
+pub fn handle_xclaim(args: Vec<Value>, store: &Store) -> Result<(Value, Vec<Value>)> {
+    let args = unpack_args(&args)?;
+    if args.len() < 5 {
+        return Err(CommandError::WrongArity("xclaim").into());
+    }
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let min_idle = parse_int(&args[3])?.max(0) as u64;
+    let mut ids = vec![parse_id(&args[4], 0)?];
+    let mut i = 5;
+    while let Some(Ok(id)) = args.get(i).map(|id| parse_id(id, 0)) {
+        ids.push(id);
+        i += 1;
+    }
+
+    let now = now_millis();
+    let mut options = ClaimOptions {
+        delivery_time: now,
+        retry_count: None,
+        just_id: false,
+    };
+    let mut force = false;
+    let mut last_id = None;
+    while i < args.len() {
+        let option = args[i].to_lowercase();
+        let value = args.get(i + 1);
+        match option.as_str() {
+            "force" => force = true,
+            "justid" => options.just_id = true,
+            "idle" | "time" | "retrycount" => {
+                let value = parse_int(value.ok_or(CommandError::Syntax)?)?.max(0) as u64;
+                match option.as_str() {
+                    "idle" => options.delivery_time = now.saturating_sub(value),
+                    "time" => options.delivery_time = value,
+                    _ => options.retry_count = Some(value),
+                }
+                i += 1;
+            }
+            "lastid" => {
+                last_id = Some(parse_id(value.ok_or(CommandError::Syntax)?, 0)?);
+                i += 1;
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+        i += 1;
+    }
+
+    let mut keyspace = store.lock();
+    let Stream {
+        entries, groups, ..
+    } = keyspace
+        .stream(key)?
+        .ok_or_else(|| CommandError::NoGroup(key.clone(), group.clone()))?;
+    let state = groups
+        .get_mut(group)
+        .ok_or_else(|| CommandError::NoGroup(key.clone(), group.clone()))?;
+    if let Some(last_id) = last_id {
+        state.last_id = state.last_id.max(last_id);
+    }
+    state.consumers.insert(consumer.clone(), now);
+
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    for id in ids {
+        if force && entries.contains_key(&id) {
+            state.pending.entry(id).or_insert_with(|| PendingEntry {
+                consumer: consumer.clone(),
+                delivery_time: now,
+                delivery_count: 0,
+            });
+        }
+        match claim(entries, state, id, consumer, min_idle, now, &options) {
+            Claim::Claimed => claimed.push(id),
+            Claim::Deleted => deleted.push(id),
+            Claim::Skipped => {}
+        }
+    }
+    Ok(claim_reply(
+        key, group, entries, state, claimed, deleted, &options,
+    ))
+}
+
+/// `XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]`
+///
+/// Like `XCLAIM` for the pending entries from `start` on, returning a cursor
+/// to continue from, the claimed entries and the IDs of the pending entries
+/// that no longer exist in the stream, which are dropped.
+pub fn handle_xautoclaim(args: Vec<Value>, store: &Store) -> Result<(Value, Vec<Value>)> {
+    let args = unpack_args(&args)?;
+    if args.len() < 5 {
+        return Err(CommandError::WrongArity("xautoclaim").into());
+    }
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let min_idle = parse_int(&args[3])?.max(0) as u64;
+    let start = range_bound(&args[4], true)?;
+    let mut count = DEFAULT_AUTOCLAIM_COUNT;
+    let mut options = ClaimOptions {
+        delivery_time: now_millis(),
+        retry_count: None,
+        just_id: false,
+    };
+    let mut i = 5;
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "count" => {
+                let value = parse_int(args.get(i + 1).ok_or(CommandError::Syntax)?)?;
+                // Like Redis, also reject counts that would overflow the
+                // attempts budget below.
+                if value <= 0 || value > i64::MAX / AUTOCLAIM_ATTEMPTS_FACTOR as i64 {
+                    return Err(CommandError::CountNotPositive.into());
+                }
+                count = value as usize;
+                i += 1;
+            }
+            "justid" => options.just_id = true,
+            _ => return Err(CommandError::Syntax.into()),
+        }
+        i += 1;
+    }
+
+    let mut keyspace = store.lock();
+    let Stream {
+        entries, groups, ..
+    } = keyspace
+        .stream(key)?
+        .ok_or_else(|| CommandError::NoGroup(key.clone(), group.clone()))?;
+    let state = groups
+        .get_mut(group)
+        .ok_or_else(|| CommandError::NoGroup(key.clone(), group.clone()))?;
+    let now = options.delivery_time;
+    state.consumers.insert(consumer.clone(), now);
+
+    // Like Redis, look at no more than ten entries per one asked for, so
+    // that a long list of recently delivered entries does not stall us.
+    let mut attempts = count * AUTOCLAIM_ATTEMPTS_FACTOR;
+    let candidates: Vec<StreamId> = match start {
+        Some(start) => state.pending.range(start..).map(|(id, _)| *id).collect(),
+        None => Vec::new(),
+    };
+    let mut cursor = StreamId::MIN;
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    for id in candidates {
+        if attempts == 0 || claimed.len() == count {
+            cursor = id;
+            break;
+        }
+        attempts -= 1;
+        match claim(entries, state, id, consumer, min_idle, now, &options) {
+            Claim::Claimed => claimed.push(id),
+            Claim::Deleted => deleted.push(id),
+            Claim::Skipped => {}
+        }
+    }
+
+    let deleted_reply = deleted
+        .iter()
+        .map(|id| Value::Bulk(id.to_string()))
+        .collect();
+    let (claimed, writes) = claim_reply(key, group, entries, state, claimed, deleted, &options);
+    Ok((
+        Value::Array(vec![
+            Value::Bulk(cursor.to_string()),
+            claimed,
+            Value::Array(deleted_reply),
+        ]),
+        writes,
+    ))
+}
+
+/// Returns the arguments of an `XCLAIM` that gives the entries at `ids`
+/// exactly the delivery state of `pending` when replayed, without relying
+/// on the clock.
+pub fn claim_args(
+    key: &str,
+    group: &str,
+    ids: &[StreamId],
+    pending: &PendingEntry,
+    last_id: StreamId,
+) -> Vec<String> {
+    let mut args = vec![
+        "XCLAIM".to_string(),
+        key.to_string(),
+        group.to_string(),
+        pending.consumer.clone(),
+        "0".to_string(),
+    ];
+    args.extend(ids.iter().map(StreamId::to_string));
+    args.extend([
+        "TIME".to_string(),
+        pending.delivery_time.to_string(),
+        "RETRYCOUNT".to_string(),
+        pending.delivery_count.to_string(),
+        "FORCE".to_string(),
+        "JUSTID".to_string(),
+        "LASTID".to_string(),
+        last_id.to_string(),
+    ]);
+    args
+}
+
+/// How `XCLAIM` and `XAUTOCLAIM` update the entries they claim.
+struct ClaimOptions {
+    delivery_time: u64,
+    /// Replaces the delivery count, which is incremented otherwise.
+    retry_count: Option<u64>,
+    /// Only return IDs, and leave the delivery count alone.
+    just_id: bool,
+}
+
+enum Claim {
+    Claimed,
+    /// The entry no longer exists in the stream and was dropped from the
+    /// pending entries list.
+    Deleted,
+    Skipped,
+}
+
+/// Transfers the pending entry `id` to `consumer` if it has been idle long
+/// enough.
+fn claim(
+    entries: &BTreeMap<StreamId, Vec<(String, String)>>,
+    state: &mut ConsumerGroup,
+    id: StreamId,
+    consumer: &str,
+    min_idle: u64,
+    now: u64,
+    options: &ClaimOptions,
+) -> Claim {
+    let pending = match state.pending.get_mut(&id) {
+        Some(pending) => pending,
+        None => return Claim::Skipped,
+    };
+    if !entries.contains_key(&id) {
+        state.pending.remove(&id);
+        return Claim::Deleted;
+    }
+    if now.saturating_sub(pending.delivery_time) < min_idle {
+        return Claim::Skipped;
+    }
+    pending.consumer = consumer.to_string();
+    pending.delivery_time = options.delivery_time;
+    match options.retry_count {
+        Some(count) => pending.delivery_count = count,
+        None if !options.just_id => pending.delivery_count += 1,
+        None => {}
+    }
+    Claim::Claimed
+}
+
+/// Builds the reply listing the claimed entries, or just their IDs, and the
+/// commands to propagate: an `XCLAIM` per claimed entry and an `XACK` for
+/// the deleted ones.
+fn claim_reply(
+    key: &str,
+    group: &str,
+    entries: &BTreeMap<StreamId, Vec<(String, String)>>,
+    state: &ConsumerGroup,
+    claimed: Vec<StreamId>,
+    deleted: Vec<StreamId>,
+    options: &ClaimOptions,
+) -> (Value, Vec<Value>) {
+    let mut writes: Vec<Value> = claimed
+        .iter()
+        .map(|id| {
+            let args = claim_args(key, group, &[*id], &state.pending[id], state.last_id);
+            bulk_array(args)
+        })
+        .collect();
+    if !deleted.is_empty() {
+        let mut command = vec!["XACK".to_string(), key.to_string(), group.to_string()];
+        command.extend(deleted.iter().map(StreamId::to_string));
+        writes.push(bulk_array(command));
+    }
+    let reply = claimed
+        .iter()
+        .map(|id| match options.just_id {
+            true => Value::Bulk(id.to_string()),
+            false => entry_reply((id, &entries[id])),
+        })
+        .collect();
+    (Value::Array(reply), writes)
+}
+
+/// The options shared by `XREAD` and `XREADGROUP`.
+struct ReadOptions {
+    count: usize,
+    block: bool,
+    deadline: Option<Instant>,
+    noack: bool,
+    keys: Vec<String>,
+    ids: Vec<String>,
+}
+
+impl ReadOptions {
+    fn parse(args: &[String], command: &'static str) -> Result<ReadOptions, CommandError> {
+        let mut options = ReadOptions {
+            count: usize::MAX,
+            block: false,
+            deadline: None,
+            noack: false,
+            keys: Vec::new(),
+            ids: Vec::new(),
+        };
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].to_lowercase().as_str() {
+                "count" => {
+                    let count = parse_int(args.get(i + 1).ok_or(CommandError::Syntax)?)?;
+                    // Zero means no limit.
+                    if count > 0 {
+                        options.count = count as usize;
+                    }
+                    i += 2;
+                }
+                "block" => {
+                    let ms = args
+                        .get(i + 1)
+                        .ok_or(CommandError::Syntax)?
+                        .parse::<i64>()
+                        .map_err(|_| CommandError::TimeoutNotInteger)?;
+                    if ms < 0 {
+                        return Err(CommandError::NegativeTimeout);
+                    }
+                    options.block = true;
+                    options.deadline =
+                        (ms > 0).then(|| Instant::now() + Duration::from_millis(ms as u64));
+                    i += 2;
+                }
+                "noack" if command == "xreadgroup" => {
+                    options.noack = true;
+                    i += 1;
+                }
+                "streams" => {
+                    let streams = &args[i + 1..];
+                    if streams.is_empty() || streams.len() & 1 != 0 {
+                        let id = if command == "xread" { "$" } else { ">" };
+                        return Err(CommandError::UnbalancedStreams(command, id));
+                    }
+                    let (keys, ids) = streams.split_at(streams.len() / 2);
+                    options.keys = keys.to_vec();
+                    options.ids = ids.to_vec();
+                    return Ok(options);
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+        Err(CommandError::Syntax)
+    }
+}
+
+/// Parses an optional `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at
+/// `args[*i]`, advancing `i` past it. Approximate trimming is done exactly, but
+/// still honours the limit on how many entries one call may remove.
+fn parse_trim(
+    args: &[String],
+    i: &mut usize,
+) -> Result<Option<(StreamTrim, Option<usize>)>, CommandError> {
+    let strategy = match args.get(*i) {
+        Some(strategy) => strategy.to_lowercase(),
+        None => return Ok(None),
+    };
+    if strategy != "maxlen" && strategy != "minid" {
+        return Ok(None);
+    }
+    *i += 1;
+    let approximate = args.get(*i).is_some_and(|operator| operator == "~");
+    if args
+        .get(*i)
+        .is_some_and(|operator| operator == "~" || operator == "=")
+    {
+        *i += 1;
+    }
+    let threshold = args.get(*i).ok_or(CommandError::Syntax)?;
+    *i += 1;
+    let trim = if strategy == "maxlen" {
+        let len = parse_int(threshold)?;
+        if len < 0 {
+            return Err(CommandError::NegativeMaxLen);
+        }
+        StreamTrim::MaxLen(len as usize)
+    } else {
+        StreamTrim::MinId(parse_id(threshold, 0)?)
+    };
+
+    let mut limit = None;
+    if args
+        .get(*i)
+        .is_some_and(|option| option.eq_ignore_ascii_case("limit"))
+    {
+        if !approximate {
+            return Err(CommandError::LimitWithoutApprox);
+        }
+        let count = parse_int(args.get(*i + 1).ok_or(CommandError::Syntax)?)?;
+        if count < 0 {
+            return Err(CommandError::NotPositive);
+        }
+        // A limit of zero means no limit.
+        limit = (count > 0).then_some(count as usize);
+        *i += 2;
+    }
+    Ok(Some((trim, limit)))
+}
+
+/// Resolves the ID argument of `XADD` against the stream's last ID: `*`
+/// generates one from the current time, `ms-*` the next sequence number
+/// within that millisecond and anything else is taken as is.
+fn next_id(arg: &str, last_id: StreamId) -> Result<StreamId, CommandError> {
+    let id = if arg == "*" {
+        let now = now_millis();
+        if now > last_id.ms {
+            StreamId { ms: now, seq: 0 }
+        } else {
+            last_id.next().ok_or(CommandError::StreamExhausted)?
+        }
+    } else if let Some(ms) = arg.strip_suffix("-*") {
+        let ms = ms
+            .parse::<u64>()
+            .map_err(|_| CommandError::InvalidStreamId)?;
+        if ms == last_id.ms {
+            let seq = last_id
+                .seq
+                .checked_add(1)
+                .ok_or(CommandError::StreamIdTooSmall)?;
+            StreamId { ms, seq }
+        } else {
+            StreamId { ms, seq: 0 }
+        }
+    } else {
+        parse_id(arg, 0)?
+    };
+    if id == StreamId::MIN {
+        return Err(CommandError::StreamIdZero);
+    }
+    if id <= last_id {
+        return Err(CommandError::StreamIdTooSmall);
+    }
+    Ok(id)
+}
+
+/// Parses `ms-seq` or just `ms`, in which case the sequence number is
+/// `default_seq`.
+fn parse_id(s: &str, default_seq: u64) -> Result<StreamId, CommandError> {
+    let (ms, seq) = match s.split_once('-') {
+        Some((ms, seq)) => (ms, seq.parse::<u64>().ok()),
+        None => (s, Some(default_seq)),
+    };
+    match (ms.parse::<u64>(), seq) {
+        (Ok(ms), Some(seq)) => Ok(StreamId { ms, seq }),
+        _ => Err(CommandError::InvalidStreamId),
+    }
+}
+
+/// Parses a range bound: `-` and `+` stand for the smallest and largest IDs
+/// and a `(` prefix excludes the ID itself. Returns `None` if an exclusive
+/// bound leaves nothing to include.
+fn range_bound(s: &str, start: bool) -> Result<Option<StreamId>, CommandError> {
+    match s {
+        "-" => return Ok(Some(StreamId::MIN)),
+        "+" => return Ok(Some(StreamId::MAX)),
+        _ => {}
+    }
+    let (s, exclusive) = match s.strip_prefix('(') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    let id = parse_id(s, if start { 0 } else { u64::MAX })?;
+    Ok(match (exclusive, start) {
+        (false, _) => Some(id),
+        (true, true) => id.next(),
+        (true, false) => id.prev(),
+    })
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Formats a stream and its entries as `[key, [entry, ...]]`.
+fn stream_reply(key: &str, entries: Vec<Value>) -> Value {
+    Value::Array(vec![Value::Bulk(key.to_string()), Value::Array(entries)])
+}
+
+/// Formats an entry as `[id, [field, value, ...]]`.
+fn entry_reply((id, fields): (&StreamId, &Vec<(String, String)>)) -> Value {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [Value::Bulk(field.clone()), Value::Bulk(value.clone())])
+        .collect();
+    Value::Array(vec![Value::Bulk(id.to_string()), Value::Array(fields)])
+}
@@ -0,0 +1,395 @@
+use anyhow::Result;
+use resp::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    db::{Data, Keyspace, RedisValue, Store},
+    error::CommandError,
+    parse_float, parse_int, unpack_args,
+};
+
+/// The longest string `SETRANGE` may produce, like Redis' default
+/// `proto-max-bulk-len`.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// What `SET` does with the time to live of the key.
+enum Ttl {
+    At(SystemTime),
+    Keep,
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+/// EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]`
+///
+/// Replies with nil instead of `OK` if `NX` or `XX` prevented the write, or
+/// with the old value if `GET` is given.
+pub fn handle_set(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let mut args = unpack_args(&args)?.into_iter();
+    let (key, value) = match (args.next(), args.next()) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return Err(CommandError::WrongArity("set").into()),
+    };
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    let mut ttl = None;
+    while let Some(option) = args.next() {
+        let option = option.to_lowercase();
+        match option.as_str() {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            "get" => get = true,
+            "keepttl" if ttl.is_none() => ttl = Some(Ttl::Keep),
+            "ex" | "px" | "exat" | "pxat" if ttl.is_none() => {
+                let time = args.next().ok_or(CommandError::Syntax)?;
+                ttl = Some(Ttl::At(parse_expiry(&option, &time, "set")?));
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+    }
+
+    let mut keyspace = store.lock();
+    let (exists, old_value, old_expiry) = match keyspace.get(&key) {
+        None => (false, None, None),
+        Some(RedisValue {
+            value: Data::String(value),
+            expiry,
+        }) => (true, get.then(|| value.clone()), *expiry),
+        Some(_) if get => return Err(CommandError::WrongType.into()),
+        Some(current) => (true, None, current.expiry),
+    };
+    let old_value = || old_value.map(Value::Bulk).unwrap_or(Value::Null);
+    if (nx && exists) || (xx && !exists) {
+        return Ok(if get { old_value() } else { Value::Null });
+    }
+    let expiry = match ttl {
+        Some(Ttl::At(at)) => Some(at),
+        Some(Ttl::Keep) => old_expiry,
+        None => None,
+    };
+    keyspace.insert(
+        key,
+        RedisValue {
+            value: Data::String(value),
+            expiry,
+        },
+    );
+    Ok(if get {
+        old_value()
+    } else {
+        Value::String("OK".to_string())
+    })
+}
+
+/// `GET key`
+pub fn handle_get(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("get").into());
+    }
+    Ok(store
+        .read(&args[0])?
+        .map(Value::Bulk)
+        .unwrap_or(Value::Null))
+}
+
+/// `GETDEL key`
+pub fn handle_getdel(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("getdel").into());
+    }
+    let mut keyspace = store.lock();
+    if keyspace.string(&args[0])?.is_none() {
+        return Ok(Value::Null);
+    }
+    match keyspace.remove(&args[0]) {
+        Some(RedisValue {
+            value: Data::String(value),
+            ..
+        }) => Ok(Value::Bulk(value)),
+        _ => Ok(Value::Null),
+    }
+}
+
+/// `GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+/// PXAT unix-time-milliseconds | PERSIST]`
+pub fn handle_getex(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    // `Some(None)` removes the expiry.
+    let expiry = match args.len() {
+        1 => None,
+        2 if args[1].eq_ignore_ascii_case("persist") => Some(None),
+        3 => {
+            let option = args[1].to_lowercase();
+            match option.as_str() {
+                "ex" | "px" | "exat" | "pxat" => {
+                    Some(Some(parse_expiry(&option, &args[2], "getex")?))
+                }
+                _ => return Err(CommandError::Syntax.into()),
+            }
+        }
+        0 => return Err(CommandError::WrongArity("getex").into()),
+        _ => return Err(CommandError::Syntax.into()),
+    };
+    let mut keyspace = store.lock();
+    let value = match keyspace.string(&args[0])? {
+        Some(value) => value.clone(),
+        None => return Ok(Value::Null),
+    };
+    if let Some(expiry) = expiry {
+        keyspace.set_expiry(&args[0], expiry);
+    }
+    Ok(Value::Bulk(value))
+}
+
+/// `MGET key [key ...]`
+///
+/// Keys that do not exist or hold another type come back as nil.
+pub fn handle_mget(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() {
+        return Err(CommandError::WrongArity("mget").into());
+    }
+    let mut keyspace = store.lock();
+    let values = args
+        .iter()
+        .map(|key| match keyspace.string(key) {
+            Ok(Some(value)) => Value::Bulk(value.clone()),
+            _ => Value::Null,
+        })
+        .collect();
+    Ok(Value::Array(values))
+}
+
+/// `MSET key value [key value ...]` and `MSETNX key value [key value ...]`,
+/// which sets nothing at all if any of the keys exists.
+pub fn handle_mset(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.is_empty() || args.len() & 1 != 0 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let mut keyspace = store.lock();
+    let nx = command == "msetnx";
+    if nx && args.chunks(2).any(|pair| keyspace.get(&pair[0]).is_some()) {
+        return Ok(Value::Integer(0));
+    }
+    for pair in args.chunks(2) {
+        let value = RedisValue::new(Data::String(pair[1].clone()));
+        keyspace.insert(pair[0].clone(), value);
+    }
+    Ok(if nx {
+        Value::Integer(1)
+    } else {
+        Value::String("OK".to_string())
+    })
+}
+
+/// `APPEND key value`
+pub fn handle_append(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("append").into());
+    }
+    let mut keyspace = store.lock();
+    let len = match keyspace.string(&args[0])? {
+        Some(value) => {
+            value.push_str(&args[1]);
+            value.len()
+        }
+        None => {
+            let value = RedisValue::new(Data::String(args[1].clone()));
+            keyspace.insert(args[0].clone(), value);
+            args[1].len()
+        }
+    };
+    Ok(Value::Integer(len as i64))
+}
+
+/// `STRLEN key`
+pub fn handle_strlen(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("strlen").into());
+    }
+    let len = store
+        .lock()
+        .string(&args[0])?
+        .map_or(0, |value| value.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `GETRANGE key start end`
+///
+/// Offsets are in bytes and inclusive, and negative ones count from the end.
+/// Values are UTF-8 strings, so a range that cuts a character is an error.
+pub fn handle_getrange(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("getrange").into());
+    }
+    let (start, end) = (parse_int(&args[1])?, parse_int(&args[2])?);
+    let mut keyspace = store.lock();
+    let value = match keyspace.string(&args[0])? {
+        Some(value) => value,
+        None => return Ok(Value::Bulk(String::new())),
+    };
+    // Unlike list ranges, a negative end before the start is clamped to the
+    // first byte instead of making the range empty.
+    if start < 0 && end < 0 && start > end {
+        return Ok(Value::Bulk(String::new()));
+    }
+    let len = value.len() as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+    if start > end {
+        return Ok(Value::Bulk(String::new()));
+    }
+    let range = value
+        .get(start as usize..=end as usize)
+        .ok_or(CommandError::SplitsCharacter)?;
+    Ok(Value::Bulk(range.to_string()))
+}
+
+/// `SETRANGE key offset value`
+///
+/// Overwrites the string from the byte at `offset` on, padding it with zero
+/// bytes if it is shorter. Returns the new length. Like with `GETRANGE`, the
+/// result has to be valid UTF-8.
+pub fn handle_setrange(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("setrange").into());
+    }
+    let offset = parse_int(&args[1])?;
+    if offset < 0 {
+        return Err(CommandError::OffsetOutOfRange.into());
+    }
+    let (offset, patch) = (offset as usize, args[2].as_bytes());
+    if offset + patch.len() > MAX_STRING_LEN {
+        return Err(CommandError::StringTooLong.into());
+    }
+
+    let mut keyspace = store.lock();
+    let current = keyspace.string(&args[0])?;
+    if patch.is_empty() {
+        // Nothing to write, so a missing key is not created either.
+        return Ok(Value::Integer(current.map_or(0, |value| value.len()) as i64));
+    }
+    let mut bytes = current
+        .map(|value| value.as_bytes().to_vec())
+        .unwrap_or_default();
+    if bytes.len() < offset + patch.len() {
+        bytes.resize(offset + patch.len(), 0);
+    }
+    bytes[offset..offset + patch.len()].copy_from_slice(patch);
+    let len = bytes.len();
+    let value = String::from_utf8(bytes).map_err(|_| CommandError::SplitsCharacter)?;
+    replace(&mut keyspace, &args[0], value)?;
+    Ok(Value::Integer(len as i64))
+}
+
+/// `INCR` / `DECR key` and `INCRBY` / `DECRBY key increment`
+///
+/// A missing key counts as zero. The key keeps its time to live.
+pub fn handle_incr(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    let arity = match command {
+        "incr" | "decr" => 1,
+        _ => 2,
+    };
+    if args.len() != arity {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let delta = match command {
+        "incr" => 1,
+        "decr" => -1,
+        "incrby" => parse_int(&args[1])?,
+        _ => parse_int(&args[1])?
+            .checked_neg()
+            .ok_or(CommandError::Overflow)?,
+    };
+
+    let mut keyspace = store.lock();
+    let current = match keyspace.string(&args[0])? {
+        Some(value) => parse_int(value)?,
+        None => 0,
+    };
+    let value = current.checked_add(delta).ok_or(CommandError::Overflow)?;
+    replace(&mut keyspace, &args[0], value.to_string())?;
+    Ok(Value::Integer(value))
+}
+
+/// `INCRBYFLOAT key increment`
+pub fn handle_incrbyfloat(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("incrbyfloat").into());
+    }
+    let increment = parse_float(&args[1])?;
+    let mut keyspace = store.lock();
+    let current = match keyspace.string(&args[0])? {
+        Some(value) => parse_float(value)?,
+        None => 0.0,
+    };
+    let value = current + increment;
+    if !value.is_finite() {
+        return Err(CommandError::NanOrInfinity.into());
+    }
+    let value = format_decimal(value);
+    replace(&mut keyspace, &args[0], value.clone())?;
+    Ok(Value::Bulk(value))
+}
+
+/// Formats an `INCRBYFLOAT` result like Redis' `%.17Lf` with the trailing
+/// zeros trimmed: never in exponent notation, and with at most 17 digits
+/// after the point. The shortest digits that read back as the same double
+/// stand in for Redis' long double rounding, so 10.1 plus 0.1 is 10.2 and
+/// not 10.199999999999999.
+fn format_decimal(value: f64) -> String {
+    let mut formatted = value.to_string();
+    if formatted
+        .split_once('.')
+        .is_some_and(|(_, fraction)| fraction.len() > 17)
+    {
+        formatted = format!("{value:.17}")
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string();
+    }
+    if formatted == "-0" {
+        formatted = "0".to_string();
+    }
+    formatted
+}
+
+/// Parses the time of an `EX`, `PX`, `EXAT` or `PXAT` option into the
+/// point in time the key expires at.
+fn parse_expiry(
+    option: &str,
+    time: &str,
+    command: &'static str,
+) -> Result<SystemTime, CommandError> {
+    let time = parse_int(time)?;
+    let invalid = || CommandError::InvalidExpire(command);
+    if time <= 0 {
+        return Err(invalid());
+    }
+    let ms = match option {
+        "ex" | "exat" => time.checked_mul(1000).ok_or_else(invalid)?,
+        _ => time,
+    };
+    let ms = Duration::from_millis(ms as u64);
+    let at = match option {
+        "ex" | "px" => SystemTime::now().checked_add(ms),
+        _ => UNIX_EPOCH.checked_add(ms),
+    };
+    at.ok_or_else(invalid)
+}
+
+/// Replaces the string at `key` in place, keeping its expiry, or creates it.
+fn replace(keyspace: &mut Keyspace, key: &str, value: String) -> Result<(), CommandError> {
+    match keyspace.string(key)? {
+        Some(current) => *current = value,
+        None => keyspace.insert(key.to_string(), RedisValue::new(Data::String(value))),
+    }
+    Ok(())
+}
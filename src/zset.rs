@@ -0,0 +1,534 @@
+use anyhow::Result;
+use resp::Value;
+use std::collections::HashMap;
+
+use crate::{
+    db::{Data, Keyspace, RedisValue, SortedEntry, SortedSet, Store},
+    error::CommandError,
+    format_float,
+    keys::{scan_reply, ScanOptions},
+    list, parse_float, parse_int, unpack_args,
+};
+
+/// `ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]`
+pub fn handle_zadd(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 {
+        return Err(CommandError::WrongArity("zadd").into());
+    }
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) =
+        (false, false, false, false, false, false);
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            "ch" => ch = true,
+            "incr" => incr = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let pairs = &args[i..];
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(CommandError::Syntax.into());
+    }
+    if nx && xx {
+        return Err(CommandError::XxAndNx.into());
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err(CommandError::GtLtNx.into());
+    }
+    if incr && pairs.len() > 2 {
+        return Err(CommandError::IncrPair.into());
+    }
+    // Every score is validated before the set is touched.
+    let pairs = pairs
+        .chunks(2)
+        .map(|pair| Ok((parse_float(&pair[0])?, &pair[1])))
+        .collect::<Result<Vec<_>, CommandError>>()?;
+
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let set = keyspace.sorted_set_or_create(key)?;
+    let (mut added, mut changed) = (0, 0);
+    let mut incr_result = None;
+    for (score, member) in pairs {
+        let score = match set.score(member) {
+            None if xx => continue,
+            None => {
+                set.insert(member.clone(), score);
+                added += 1;
+                score
+            }
+            Some(_) if nx => continue,
+            Some(current) => {
+                let score = if incr { current + score } else { score };
+                if score.is_nan() {
+                    keyspace.remove_if_empty(key);
+                    return Err(CommandError::ScoreNan.into());
+                }
+                if (gt && score <= current) || (lt && score >= current) {
+                    continue;
+                }
+                if score != current {
+                    set.insert(member.clone(), score);
+                    changed += 1;
+                }
+                score
+            }
+        };
+        incr_result = Some(score);
+    }
+    keyspace.remove_if_empty(key);
+
+    if incr {
+        // Null when a flag prevented the update.
+        return Ok(incr_result
+            .map(|score| Value::Bulk(format_float(score)))
+            .unwrap_or(Value::Null));
+    }
+    Ok(Value::Integer(if ch { added + changed } else { added }))
+}
+
+/// `ZINCRBY key increment member`
+pub fn handle_zincrby(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 3 {
+        return Err(CommandError::WrongArity("zincrby").into());
+    }
+    let increment = parse_float(&args[1])?;
+    let mut keyspace = store.lock();
+    let set = keyspace.sorted_set_or_create(&args[0])?;
+    let score = set.score(&args[2]).unwrap_or(0.0) + increment;
+    if score.is_nan() {
+        return Err(CommandError::ScoreNan.into());
+    }
+    set.insert(args[2].clone(), score);
+    Ok(Value::Bulk(format_float(score)))
+}
+
+/// `ZREM key member [member ...]`
+pub fn handle_zrem(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("zrem").into());
+    }
+    let key = &args[0];
+    let mut keyspace = store.lock();
+    let set = match keyspace.sorted_set(key)? {
+        Some(set) => set,
+        None => return Ok(Value::Integer(0)),
+    };
+    let removed = args[1..].iter().filter(|member| set.remove(member)).count();
+    keyspace.remove_if_empty(key);
+    Ok(Value::Integer(removed as i64))
+}
+
+/// `ZSCAN key cursor [MATCH pattern] [COUNT count]`
+pub fn handle_zscan(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("zscan").into());
+    }
+    let options = ScanOptions::parse(&args[1], &args[2..], "")?;
+    let mut keyspace = store.lock();
+    let set = keyspace.sorted_set(&args[0])?;
+    let (next, elements) = set.map_or((0, Vec::new()), |set| {
+        set.scan(options.cursor, options.count)
+    });
+    let mut page = Vec::new();
+    for (member, score) in elements {
+        if options.matches(member) {
+            page.push(Value::Bulk(member.clone()));
+            page.push(Value::Bulk(format_float(score)));
+        }
+    }
+    Ok(scan_reply(next, page))
+}
+
+/// `ZSCORE key member`
+pub fn handle_zscore(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 {
+        return Err(CommandError::WrongArity("zscore").into());
+    }
+    let score = store
+        .lock()
+        .sorted_set(&args[0])?
+        .and_then(|set| set.score(&args[1]));
+    Ok(score
+        .map(|score| Value::Bulk(format_float(score)))
+        .unwrap_or(Value::Null))
+}
+
+/// `ZCARD key`
+pub fn handle_zcard(args: Vec<Value>, store: &Store) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 1 {
+        return Err(CommandError::WrongArity("zcard").into());
+    }
+    let len = store
+        .lock()
+        .sorted_set(&args[0])?
+        .map_or(0, |set| set.len());
+    Ok(Value::Integer(len as i64))
+}
+
+/// `ZRANK` / `ZREVRANK key member [WITHSCORE]`
+pub fn handle_zrank(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() != 2 && args.len() != 3 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let withscore = match args.get(2) {
+        Some(option) if option.eq_ignore_ascii_case("withscore") => true,
+        Some(_) => return Err(CommandError::Syntax.into()),
+        None => false,
+    };
+    let mut keyspace = store.lock();
+    let set = keyspace.sorted_set(&args[0])?;
+    let found = set.and_then(|set| {
+        let rank = set.rank(&args[1])?;
+        let rank = if command == "zrevrank" {
+            set.len() - 1 - rank
+        } else {
+            rank
+        };
+        Some((rank, set.score(&args[1])?))
+    });
+    Ok(match found {
+        Some((rank, score)) if withscore => Value::Array(vec![
+            Value::Integer(rank as i64),
+            Value::Bulk(format_float(score)),
+        ]),
+        Some((rank, _)) => Value::Integer(rank as i64),
+        None if withscore => Value::NullArray,
+        None => Value::Null,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum By {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// `ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]`
+/// and the older `ZRANGEBYSCORE`, `ZREVRANGEBYSCORE` and `ZREVRANGE`, which
+/// are the same query with some options implied.
+pub fn handle_zrange(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let (mut by, mut rev) = match command {
+        "zrangebyscore" => (By::Score, false),
+        "zrevrangebyscore" => (By::Score, true),
+        "zrevrange" => (By::Rank, true),
+        _ => (By::Rank, false),
+    };
+    let mut limit = None;
+    let mut withscores = false;
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_lowercase().as_str() {
+            "byscore" if command == "zrange" => by = By::Score,
+            "bylex" if command == "zrange" => by = By::Lex,
+            "rev" if command == "zrange" => rev = true,
+            "limit" if command != "zrevrange" => {
+                let offset = parse_int(options.next().ok_or(CommandError::Syntax)?)?;
+                let count = parse_int(options.next().ok_or(CommandError::Syntax)?)?;
+                limit = Some((offset, count));
+            }
+            "withscores" => withscores = true,
+            _ => return Err(CommandError::Syntax.into()),
+        }
+    }
+    if limit.is_some() && by == By::Rank {
+        return Err(CommandError::LimitWithoutBy.into());
+    }
+    if withscores && by == By::Lex {
+        return Err(CommandError::WithScoresByLex.into());
+    }
+
+    // In reverse, score and lex ranges are given from the highest bound.
+    let (start, stop) = match (by, rev) {
+        (By::Rank, _) | (_, false) => (&args[1], &args[2]),
+        (_, true) => (&args[2], &args[1]),
+    };
+    let (ranks, start, stop) = match by {
+        By::Rank => (None, parse_int(start)?, parse_int(stop)?),
+        By::Score => {
+            let (min, max) = (ScoreBound::parse(start)?, ScoreBound::parse(stop)?);
+            (Some(Bounds::Score(min, max)), 0, 0)
+        }
+        By::Lex => {
+            let (min, max) = (LexBound::parse(start)?, LexBound::parse(stop)?);
+            (Some(Bounds::Lex(min, max)), 0, 0)
+        }
+    };
+
+    let mut keyspace = store.lock();
+    let set = match keyspace.sorted_set(&args[0])? {
+        Some(set) => set,
+        None => return Ok(Value::Array(Vec::new())),
+    };
+    let len = set.len();
+    let (from, to) = match ranks {
+        None => match list::range(start, stop, len) {
+            // Rank ranges count from the end when reversed.
+            Some((start, stop)) if rev => (len - 1 - stop, len - start),
+            Some((start, stop)) => (start, stop + 1),
+            None => (0, 0),
+        },
+        Some(bounds) => {
+            let (from, to) = bounds.ranks(set);
+            apply_limit(from, to, limit, rev)
+        }
+    };
+    let mut elements = set.range(from, to.max(from));
+    if rev {
+        elements.reverse();
+    }
+    let reply = elements
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let score = withscores.then(|| Value::Bulk(format_float(score)));
+            std::iter::once(Value::Bulk(member)).chain(score)
+        })
+        .collect();
+    Ok(Value::Array(reply))
+}
+
+/// Narrows the ranks `from..to` to `LIMIT offset count`, where the offset
+/// counts from the end of the range when it is returned in reverse.
+fn apply_limit(from: usize, to: usize, limit: Option<(i64, i64)>, rev: bool) -> (usize, usize) {
+    let (offset, count) = match limit {
+        Some((offset, _)) if offset < 0 => return (0, 0),
+        Some((offset, count)) => (offset as usize, usize::try_from(count).ok()),
+        None => return (from, to),
+    };
+    let available = to.saturating_sub(from).saturating_sub(offset);
+    let taken = count.map_or(available, |count| count.min(available));
+    if rev {
+        let end = to - offset.min(to - from);
+        (end - taken, end)
+    } else {
+        let start = from + offset.min(to - from);
+        (start, start + taken)
+    }
+}
+
+enum Bounds {
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+impl Bounds {
+    /// The ranks `from..to` of the elements within the bounds.
+    fn ranks(&self, set: &SortedSet) -> (usize, usize) {
+        match self {
+            Bounds::Score(min, max) => (
+                set.count_while(|entry| min.below(entry.score)),
+                set.count_while(|entry| !max.above(entry.score)),
+            ),
+            Bounds::Lex(min, max) => (
+                set.count_while(|entry| min.below(entry)),
+                set.count_while(|entry| !max.above(entry)),
+            ),
+        }
+    }
+}
+
+/// A score range bound, exclusive when prefixed with `(`.
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(s: &str) -> Result<Self, CommandError> {
+        let (value, exclusive) = match s.strip_prefix('(') {
+            Some(value) => (value, true),
+            None => (s, false),
+        };
+        let value = parse_float(value).map_err(|_| CommandError::MinMaxNotFloat)?;
+        Ok(ScoreBound { value, exclusive })
+    }
+
+    /// Whether `score` is below the range this bound starts.
+    fn below(&self, score: f64) -> bool {
+        score < self.value || (self.exclusive && score == self.value)
+    }
+
+    /// Whether `score` is above the range this bound ends.
+    fn above(&self, score: f64) -> bool {
+        score > self.value || (self.exclusive && score == self.value)
+    }
+}
+
+/// A lexicographical range bound: `-`, `+`, `[inclusive` or `(exclusive`.
+/// Like in Redis, lex ranges assume all elements have the same score.
+enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn parse(s: &str) -> Result<Self, CommandError> {
+        match s.chars().next() {
+            Some('-') if s.len() == 1 => Ok(LexBound::Min),
+            Some('+') if s.len() == 1 => Ok(LexBound::Max),
+            Some('[') => Ok(LexBound::Inclusive(s[1..].to_string())),
+            Some('(') => Ok(LexBound::Exclusive(s[1..].to_string())),
+            _ => Err(CommandError::MinMaxNotLex),
+        }
+    }
+
+    fn below(&self, entry: &SortedEntry) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(value) => entry.member < value.as_str(),
+            LexBound::Exclusive(value) => entry.member <= value.as_str(),
+        }
+    }
+
+    fn above(&self, entry: &SortedEntry) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(value) => entry.member > value.as_str(),
+            LexBound::Exclusive(value) => entry.member >= value.as_str(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // Adding opposite infinities gives NaN, which Redis turns into 0.
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+/// `ZUNIONSTORE` / `ZINTERSTORE destination numkeys key [key ...] [WEIGHTS weight [weight ...]] [AGGREGATE SUM | MIN | MAX]`
+///
+/// Plain sets can be used as input, with every member scoring 1.
+pub fn handle_zstore(args: Vec<Value>, store: &Store, command: &'static str) -> Result<Value> {
+    let args = unpack_args(&args)?;
+    if args.len() < 3 {
+        return Err(CommandError::WrongArity(command).into());
+    }
+    let numkeys = parse_int(&args[1])?;
+    if numkeys < 1 {
+        return Err(CommandError::NoInputKeys(command).into());
+    }
+    let numkeys = numkeys as usize;
+    if args.len() < 2 + numkeys {
+        return Err(CommandError::Syntax.into());
+    }
+    let keys = &args[2..2 + numkeys];
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut options = args[2 + numkeys..].iter();
+    while let Some(option) = options.next() {
+        match option.to_lowercase().as_str() {
+            "weights" => {
+                for weight in weights.iter_mut() {
+                    let arg = options.next().ok_or(CommandError::Syntax)?;
+                    *weight = parse_float(arg).map_err(|_| CommandError::WeightNotFloat)?;
+                }
+            }
+            "aggregate" => {
+                let arg = options.next().ok_or(CommandError::Syntax)?;
+                aggregate = match arg.to_lowercase().as_str() {
+                    "sum" => Aggregate::Sum,
+                    "min" => Aggregate::Min,
+                    "max" => Aggregate::Max,
+                    _ => return Err(CommandError::Syntax.into()),
+                };
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+    }
+
+    let mut keyspace = store.lock();
+    let mut result: Option<HashMap<String, f64>> = None;
+    for (key, weight) in keys.iter().zip(weights) {
+        let input = weighted_members(&mut keyspace, key, weight)?;
+        result = Some(match result {
+            None => input,
+            Some(mut acc) if command == "zunionstore" => {
+                for (member, score) in input {
+                    acc.entry(member)
+                        .and_modify(|current| *current = aggregate.apply(*current, score))
+                        .or_insert(score);
+                }
+                acc
+            }
+            Some(acc) => acc
+                .into_iter()
+                .filter_map(|(member, current)| {
+                    let score = *input.get(&member)?;
+                    Some((member, aggregate.apply(current, score)))
+                })
+                .collect(),
+        });
+    }
+
+    let mut set = SortedSet::new();
+    for (member, score) in result.unwrap_or_default() {
+        set.insert(member, score);
+    }
+    let len = set.len();
+    let destination = &args[0];
+    keyspace.insert(destination.clone(), RedisValue::new(Data::SortedSet(set)));
+    keyspace.remove_if_empty(destination);
+    Ok(Value::Integer(len as i64))
+}
+
+/// The members of the sorted set or set at `key` with their scores multiplied by `weight`.
+fn weighted_members(
+    keyspace: &mut Keyspace,
+    key: &str,
+    weight: f64,
+) -> Result<HashMap<String, f64>, CommandError> {
+    let weigh = |score: f64| zero_if_nan(score * weight);
+    match keyspace.get(key).map(|data| &data.value) {
+        None => Ok(HashMap::new()),
+        Some(Data::SortedSet(set)) => Ok(set
+            .iter()
+            .map(|(member, score)| (member.clone(), weigh(score)))
+            .collect()),
+        Some(Data::Set(set)) => Ok(set
+            .iter()
+            .map(|member| (member.clone(), weigh(1.0)))
+            .collect()),
+        Some(_) => Err(CommandError::WrongType),
+    }
+}
+
+fn zero_if_nan(f: f64) -> f64 {
+    if f.is_nan() {
+        0.0
+    } else {
+        f
+    }
+}